@@ -1,13 +1,27 @@
-use crate::lexer::Operator;
-use crate::parser::{ExprAST, Prototype};
+use crate::lexer::{Operator, Span};
+use crate::parser::{ExprAST, ExprKind, Prototype};
 use std::collections::HashMap;
 use std::ffi::{CStr, CString};
-use std::os::raw::c_uint;
+use std::fmt;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::path::Path;
+use std::sync::Once;
 
-use llvm_sys::analysis::{LLVMVerifierFailureAction, LLVMVerifyFunction};
+use llvm_sys::analysis::{LLVMVerifierFailureAction, LLVMVerifyFunction, LLVMVerifyModule};
+use llvm_sys::bit_writer::LLVMWriteBitcodeToFile;
 use llvm_sys::core::*;
+use llvm_sys::debuginfo::*;
+use llvm_sys::execution_engine::*;
 use llvm_sys::prelude::*;
+use llvm_sys::support::{LLVMAddSymbol, LLVMLoadLibraryPermanently, LLVMSearchForAddressOfSymbol};
+use llvm_sys::target::*;
+use llvm_sys::target_machine::*;
 use llvm_sys::transforms::scalar::*;
+use llvm_sys::transforms::util::LLVMAddPromoteMemoryToRegisterPass;
+use llvm_sys::LLVMIntPredicate::{self, *};
+use llvm_sys::LLVMRealPredicate::{self, *};
+use llvm_sys::LLVMTypeKind;
+use llvm_sys::{LLVMLinkage, LLVMModuleFlagBehavior, LLVMOpcode, LLVMUnnamedAddr};
 
 #[allow(non_camel_case_types)]
 type size_t = usize;
@@ -16,12 +30,71 @@ type size_t = usize;
 pub enum LLVMError {
     VariableNotFound(String),
     FunctionNotFound(String),
-    InvalidArgumentsSize(String, usize),
+    // The callee, the number of arguments given and the number expected.
+    InvalidArgumentsSize(String, usize, usize),
+    PrototypeMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    InvalidAssignment,
+    MisplacedString,
+    NotAnExpression,
+    ExecutionEngine(String),
+    UnresolvedExtern(String),
+    CodeGen(String),
+    UnknownTarget(String),
+    WriteBitcode(String),
+    VerificationFailed(String),
 }
 
+impl fmt::Display for LLVMError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::VariableNotFound(name) => write!(f, "variable '{}' not found", name),
+            Self::FunctionNotFound(name) => write!(f, "function '{}' not found", name),
+            Self::InvalidArgumentsSize(name, got, expected) => write!(
+                f,
+                "function '{}' expects {} arguments but got {}",
+                name, expected, got
+            ),
+            Self::PrototypeMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "function '{}' was declared with {} arguments but now has {}",
+                name, expected, found
+            ),
+            Self::InvalidAssignment => write!(f, "the left-hand side of '=' must be a variable"),
+            Self::MisplacedString => {
+                write!(f, "a string literal can only be an argument of a call")
+            }
+            Self::NotAnExpression => write!(f, "not a top-level expression"),
+            Self::ExecutionEngine(message) => {
+                write!(f, "failed to create execution engine: {}", message)
+            }
+            Self::UnresolvedExtern(name) => write!(
+                f,
+                "extern function '{}' is not defined in this process",
+                name
+            ),
+            Self::CodeGen(message) => write!(f, "failed to emit code: {}", message),
+            Self::UnknownTarget(message) => write!(f, "unknown target: {}", message),
+            Self::WriteBitcode(path) => write!(f, "failed to write bitcode to '{}'", path),
+            Self::VerificationFailed(message) => {
+                write!(f, "invalid module: {}", message.trim_end())
+            }
+        }
+    }
+}
+
+impl std::error::Error for LLVMError {}
+
 type Result<T> = std::result::Result<T, LLVMError>;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct LLVMValue {
     ptr: LLVMValueRef,
 }
@@ -38,12 +111,49 @@ impl LLVMValue {
     }
 
     pub fn dump(&self) {
+        eprint!("{}", self);
+    }
+
+    pub fn is_integer(&self) -> bool {
+        unsafe { LLVMGetTypeKind(LLVMTypeOf(self.ptr)) == LLVMTypeKind::LLVMIntegerTypeKind }
+    }
+
+    /// The value of a constant double, or of a constant integer as a double.
+    /// `None` if the value is only known at run time.
+    pub fn as_const_double(&self) -> Option<f64> {
+        unsafe {
+            if !LLVMIsAConstantFP(self.ptr).is_null() {
+                let mut loses_info = 0;
+                Some(LLVMConstRealGetDouble(self.ptr, &mut loses_info))
+            } else if !LLVMIsAConstantInt(self.ptr).is_null() {
+                Some(LLVMConstIntGetSExtValue(self.ptr) as f64)
+            } else {
+                None
+            }
+        }
+    }
+
+    pub fn add_incoming(&self, incoming: &[(&LLVMValue, LLVMBasicBlockRef)]) {
+        let mut values: Vec<_> = incoming.iter().map(|(value, _)| value.ptr).collect();
+        let mut blocks: Vec<_> = incoming.iter().map(|(_, block)| *block).collect();
         unsafe {
-            LLVMDumpValue(self.ptr);
+            LLVMAddIncoming(
+                self.ptr,
+                values.as_mut_ptr(),
+                blocks.as_mut_ptr(),
+                incoming.len() as c_uint,
+            );
         }
     }
 }
 
+impl fmt::Display for LLVMValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ir = take_message(unsafe { LLVMPrintValueToString(self.ptr) });
+        write!(f, "{}", ir)
+    }
+}
+
 impl From<FunctionRef> for LLVMValue {
     fn from(f: FunctionRef) -> Self {
         Self { ptr: f.ptr }
@@ -72,27 +182,90 @@ impl FunctionRef {
         args.iter().map(|ptr| LLVMValue::new(*ptr)).collect()
     }
 
-    pub fn verify(&self, action: LLVMVerifierFailureAction) {
+    pub fn name(&self) -> String {
+        LLVMValue::new(self.ptr).name()
+    }
+
+    /// Whether this is only a declaration, such as from `extern`, without a
+    /// body.
+    pub fn is_declaration(&self) -> bool {
+        unsafe { LLVMCountBasicBlocks(self.ptr) == 0 }
+    }
+
+    /// Whether the function alone is valid IR.
+    pub fn verify(&self) -> bool {
+        unsafe {
+            LLVMVerifyFunction(self.ptr, LLVMVerifierFailureAction::LLVMReturnStatusAction) == 0
+        }
+    }
+
+    /// The result of the function if its body folded to a single `ret` of a
+    /// constant. Any other instruction, such as a call or a store, may have
+    /// side effects, so the function has to run.
+    pub fn constant_result(&self) -> Option<f64> {
+        if unsafe { LLVMCountBasicBlocks(self.ptr) } != 1 {
+            return None;
+        }
         unsafe {
-            LLVMVerifyFunction(self.ptr, action);
+            let ret = LLVMGetBasicBlockTerminator(self.entry_block());
+            if ret.is_null()
+                || LLVMGetFirstInstruction(self.entry_block()) != ret
+                || LLVMGetInstructionOpcode(ret) != LLVMOpcode::LLVMRet
+                || LLVMGetNumOperands(ret) != 1
+            {
+                return None;
+            }
+            LLVMValue::new(LLVMGetOperand(ret, 0)).as_const_double()
         }
     }
 
-    pub fn delete(&self) {
+    pub fn entry_block(&self) -> LLVMBasicBlockRef {
+        unsafe { LLVMGetEntryBasicBlock(self.ptr) }
+    }
+
+    pub(crate) fn delete(&self) {
         unsafe {
             LLVMDeleteFunction(self.ptr);
         }
     }
+
+    /// Redirects every use of this function to `new`, deletes this function
+    /// and gives its name to `new`.
+    pub(crate) fn replace_with(&self, new: &FunctionRef) {
+        let name = self.name();
+        unsafe {
+            LLVMReplaceAllUsesWith(self.ptr, new.ptr);
+        }
+        self.delete();
+        let name = CString::new(name).unwrap();
+        let len = name.as_bytes().len();
+        unsafe {
+            LLVMSetValueName2(new.ptr, name.as_ptr(), len);
+        }
+    }
+}
+
+impl fmt::Display for FunctionRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ir = take_message(unsafe { LLVMPrintValueToString(self.ptr) });
+        write!(f, "{}", ir)
+    }
 }
 
-pub struct LLVMContext {
+pub(crate) struct LLVMContext {
     inner: LLVMContextRef,
+    // Types are uniqued per context, so these never change.
+    double_type: LLVMTypeRef,
+    i64_type: LLVMTypeRef,
 }
 
 impl LLVMContext {
     pub fn new() -> Self {
+        let inner = unsafe { LLVMContextCreate() };
         Self {
-            inner: unsafe { LLVMContextCreate() },
+            inner,
+            double_type: unsafe { LLVMDoubleTypeInContext(inner) },
+            i64_type: unsafe { LLVMInt64TypeInContext(inner) },
         }
     }
 
@@ -103,13 +276,25 @@ impl LLVMContext {
         }
     }
 
-    pub fn create_basic_block(&mut self, f: &FunctionRef) -> LLVMBasicBlockRef {
-        let name = CStr::from_bytes_with_nul(b"entry\0").unwrap();
+    pub fn append_basic_block(&mut self, f: &FunctionRef, name: &str) -> LLVMBasicBlockRef {
+        let name = CString::new(name).unwrap();
         unsafe { LLVMAppendBasicBlockInContext(self.inner, f.ptr, name.as_ptr()) }
     }
 
-    pub fn get_double_type(&mut self) -> LLVMTypeRef {
-        unsafe { LLVMDoubleTypeInContext(self.inner) }
+    pub fn get_double_type(&self) -> LLVMTypeRef {
+        self.double_type
+    }
+
+    pub fn get_i64_type(&self) -> LLVMTypeRef {
+        self.i64_type
+    }
+
+    pub fn const_double(&mut self, value: f64) -> LLVMValue {
+        LLVMValue::new(unsafe { LLVMConstReal(self.get_double_type(), value) })
+    }
+
+    pub fn const_i64(&mut self, value: i64) -> LLVMValue {
+        LLVMValue::new(unsafe { LLVMConstInt(self.get_i64_type(), value as u64, true as LLVMBool) })
     }
 }
 
@@ -121,7 +306,9 @@ impl Drop for LLVMContext {
     }
 }
 
-pub struct LLVMModule {
+/// Owns the underlying module and disposes it on drop. The module is never
+/// handed over to LLVM: `ExecutionEngine::new` compiles a clone instead.
+pub(crate) struct LLVMModule {
     inner: LLVMModuleRef,
 }
 
@@ -141,9 +328,42 @@ impl LLVMModule {
         let ptr = unsafe { LLVMAddFunction(self.inner, name.as_ptr(), ty) };
         FunctionRef::new(ptr)
     }
+
+    /// The first called function that is only declared and that neither the
+    /// host process nor `register_extern` provides. MCJIT aborts the process
+    /// on such a call instead of returning an error.
+    fn find_unresolved_extern(&self) -> Option<String> {
+        unsafe {
+            // Symbols of the host process are searched once it is loaded.
+            LLVMLoadLibraryPermanently(std::ptr::null());
+            let mut ptr = LLVMGetFirstFunction(self.inner);
+            while !ptr.is_null() {
+                let f = FunctionRef::new(ptr);
+                if f.is_declaration()
+                    && LLVMGetIntrinsicID(ptr) == 0
+                    && !LLVMGetFirstUse(ptr).is_null()
+                {
+                    let name = CString::new(f.name()).unwrap();
+                    if LLVMSearchForAddressOfSymbol(name.as_ptr()).is_null() {
+                        return Some(f.name());
+                    }
+                }
+                ptr = LLVMGetNextFunction(ptr);
+            }
+        }
+        None
+    }
+}
+
+impl Drop for LLVMModule {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMDisposeModule(self.inner);
+        }
+    }
 }
 
-pub struct LLVMBuilder {
+pub(crate) struct LLVMBuilder {
     inner: LLVMBuilderRef,
     ty: LLVMTypeRef,
 }
@@ -174,30 +394,166 @@ impl LLVMBuilder {
         LLVMValue::new(ptr)
     }
 
-    pub fn create_fcmp(&mut self, lhs: &LLVMValue, rhs: &LLVMValue) -> LLVMValue {
-        let ptr = unsafe {
-            let name = CStr::from_bytes_with_nul(b"cmptmp\0").unwrap();
-            let l = LLVMBuildFCmp(
-                self.inner,
-                llvm_sys::LLVMRealPredicate::LLVMRealOLT,
-                lhs.ptr,
-                rhs.ptr,
-                name.as_ptr(),
-            );
-            let name = CStr::from_bytes_with_nul(b"booltmp\0").unwrap();
-            LLVMBuildUIToFP(self.inner, l, self.ty, name.as_ptr())
-        };
+    pub fn create_fdiv(&mut self, lhs: &LLVMValue, rhs: &LLVMValue) -> LLVMValue {
+        let name = CStr::from_bytes_with_nul(b"divtmp\0").unwrap();
+        let ptr = unsafe { LLVMBuildFDiv(self.inner, lhs.ptr, rhs.ptr, name.as_ptr()) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_add(&mut self, lhs: &LLVMValue, rhs: &LLVMValue) -> LLVMValue {
+        let name = CStr::from_bytes_with_nul(b"addtmp\0").unwrap();
+        let ptr = unsafe { LLVMBuildAdd(self.inner, lhs.ptr, rhs.ptr, name.as_ptr()) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_sub(&mut self, lhs: &LLVMValue, rhs: &LLVMValue) -> LLVMValue {
+        let name = CStr::from_bytes_with_nul(b"subtmp\0").unwrap();
+        let ptr = unsafe { LLVMBuildSub(self.inner, lhs.ptr, rhs.ptr, name.as_ptr()) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_mul(&mut self, lhs: &LLVMValue, rhs: &LLVMValue) -> LLVMValue {
+        let name = CStr::from_bytes_with_nul(b"multmp\0").unwrap();
+        let ptr = unsafe { LLVMBuildMul(self.inner, lhs.ptr, rhs.ptr, name.as_ptr()) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_neg(&mut self, value: &LLVMValue) -> LLVMValue {
+        let name = CStr::from_bytes_with_nul(b"negtmp\0").unwrap();
+        let ptr = unsafe { LLVMBuildNeg(self.inner, value.ptr, name.as_ptr()) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_icmp(
+        &mut self,
+        pred: LLVMIntPredicate,
+        lhs: &LLVMValue,
+        rhs: &LLVMValue,
+    ) -> LLVMValue {
+        let name = CStr::from_bytes_with_nul(b"cmptmp\0").unwrap();
+        let ptr = unsafe { LLVMBuildICmp(self.inner, pred, lhs.ptr, rhs.ptr, name.as_ptr()) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_si_to_fp(&mut self, value: &LLVMValue) -> LLVMValue {
+        let name = CStr::from_bytes_with_nul(b"casttmp\0").unwrap();
+        let ptr = unsafe { LLVMBuildSIToFP(self.inner, value.ptr, self.ty, name.as_ptr()) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_fneg(&mut self, value: &LLVMValue) -> LLVMValue {
+        let name = CStr::from_bytes_with_nul(b"negtmp\0").unwrap();
+        let ptr = unsafe { LLVMBuildFNeg(self.inner, value.ptr, name.as_ptr()) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_fcmp(
+        &mut self,
+        pred: LLVMRealPredicate,
+        lhs: &LLVMValue,
+        rhs: &LLVMValue,
+    ) -> LLVMValue {
+        let name = CStr::from_bytes_with_nul(b"cmptmp\0").unwrap();
+        let ptr = unsafe { LLVMBuildFCmp(self.inner, pred, lhs.ptr, rhs.ptr, name.as_ptr()) };
+        LLVMValue::new(ptr)
+    }
+
+    /// Compares a number against zero, giving an `i1` that is true unless it
+    /// is zero, for use as a branch condition.
+    pub fn create_to_bool(&mut self, value: &LLVMValue) -> LLVMValue {
+        let ty = unsafe { LLVMTypeOf(value.ptr) };
+        if value.is_integer() {
+            let zero = LLVMValue::new(unsafe { LLVMConstInt(ty, 0, false as LLVMBool) });
+            self.create_icmp(LLVMIntNE, value, &zero)
+        } else {
+            let zero = LLVMValue::new(unsafe { LLVMConstReal(ty, 0.0) });
+            self.create_fcmp(LLVMRealONE, value, &zero)
+        }
+    }
+
+    pub fn create_ui_to_fp(&mut self, value: &LLVMValue) -> LLVMValue {
+        let name = CStr::from_bytes_with_nul(b"booltmp\0").unwrap();
+        let ptr = unsafe { LLVMBuildUIToFP(self.inner, value.ptr, self.ty, name.as_ptr()) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_br(&mut self, dest: LLVMBasicBlockRef) -> LLVMValue {
+        let ptr = unsafe { LLVMBuildBr(self.inner, dest) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_cond_br(
+        &mut self,
+        cond: &LLVMValue,
+        then_block: LLVMBasicBlockRef,
+        else_block: LLVMBasicBlockRef,
+    ) -> LLVMValue {
+        let ptr = unsafe { LLVMBuildCondBr(self.inner, cond.ptr, then_block, else_block) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_phi(
+        &mut self,
+        name: &str,
+        incoming: &[(&LLVMValue, LLVMBasicBlockRef)],
+    ) -> LLVMValue {
+        let name = CString::new(name).unwrap();
+        let ty = unsafe { LLVMTypeOf(incoming[0].0.ptr) };
+        let phi = LLVMValue::new(unsafe { LLVMBuildPhi(self.inner, ty, name.as_ptr()) });
+        phi.add_incoming(incoming);
+        phi
+    }
+
+    pub fn create_alloca(&mut self, name: &str) -> LLVMValue {
+        let name = CString::new(name).unwrap();
+        let ptr = unsafe { LLVMBuildAlloca(self.inner, self.ty, name.as_ptr()) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_load(&mut self, ptr: &LLVMValue, name: &str) -> LLVMValue {
+        let name = CString::new(name).unwrap();
+        let ptr = unsafe { LLVMBuildLoad(self.inner, ptr.ptr, name.as_ptr()) };
+        LLVMValue::new(ptr)
+    }
+
+    pub fn create_store(&mut self, value: &LLVMValue, ptr: &LLVMValue) -> LLVMValue {
+        let ptr = unsafe { LLVMBuildStore(self.inner, value.ptr, ptr.ptr) };
         LLVMValue::new(ptr)
     }
 
     pub fn create_call(&mut self, callee: &FunctionRef, args: Vec<LLVMValue>) -> LLVMValue {
         let mut args: Vec<_> = args.into_iter().map(|v| v.ptr).collect();
         let num_args = args.len();
+        let mut arg_types: Vec<_> = args.iter().map(|&arg| unsafe { LLVMTypeOf(arg) }).collect();
+        // Prototypes only have doubles, so an argument of another type (a
+        // string) is passed by calling through a cast to the actual types.
+        // The cast is not variadic and returns a double, so the callee must
+        // be declared that way in C too: a string is only ever an `i8*`
+        // argument, and variadic functions such as `printf` cannot be called.
+        let callee = if arg_types.iter().any(|&ty| ty != self.ty) {
+            let name = CStr::from_bytes_with_nul(b"calleecast\0").unwrap();
+            unsafe {
+                let f_type = LLVMFunctionType(
+                    self.ty,
+                    arg_types.as_mut_ptr(),
+                    num_args as c_uint,
+                    false as LLVMBool,
+                );
+                LLVMBuildBitCast(
+                    self.inner,
+                    callee.ptr,
+                    LLVMPointerType(f_type, 0),
+                    name.as_ptr(),
+                )
+            }
+        } else {
+            callee.ptr
+        };
         let name = CStr::from_bytes_with_nul(b"calltmp\0").unwrap();
         let ptr = unsafe {
             LLVMBuildCall(
                 self.inner,
-                callee.ptr,
+                callee,
                 args.as_mut_ptr(),
                 num_args as c_uint,
                 name.as_ptr(),
@@ -206,16 +562,84 @@ impl LLVMBuilder {
         LLVMValue::new(ptr)
     }
 
+    /// Creates a global constant holding `value` and a terminating NUL, and
+    /// returns an `i8*` to it. The length is given explicitly, so `value` may
+    /// contain NUL itself.
+    pub fn create_global_string(&mut self, value: &str) -> LLVMValue {
+        let name = CStr::from_bytes_with_nul(b"str\0").unwrap();
+        unsafe {
+            let context = LLVMGetTypeContext(self.ty);
+            let module = LLVMGetGlobalParent(self.get_insert_function().ptr);
+            let init = LLVMConstStringInContext(
+                context,
+                value.as_ptr() as *const c_char,
+                value.len() as c_uint,
+                false as LLVMBool,
+            );
+            let global = LLVMAddGlobal(module, LLVMTypeOf(init), name.as_ptr());
+            LLVMSetInitializer(global, init);
+            LLVMSetGlobalConstant(global, true as LLVMBool);
+            LLVMSetLinkage(global, LLVMLinkage::LLVMPrivateLinkage);
+            LLVMSetUnnamedAddress(global, LLVMUnnamedAddr::LLVMGlobalUnnamedAddr);
+            LLVMSetAlignment(global, 1);
+            let zero = LLVMConstInt(LLVMInt32TypeInContext(context), 0, false as LLVMBool);
+            let mut indices = [zero, zero];
+            LLVMValue::new(LLVMConstInBoundsGEP(
+                global,
+                indices.as_mut_ptr(),
+                indices.len() as c_uint,
+            ))
+        }
+    }
+
+    pub fn get_insert_block(&self) -> LLVMBasicBlockRef {
+        unsafe { LLVMGetInsertBlock(self.inner) }
+    }
+
+    pub fn get_insert_function(&self) -> FunctionRef {
+        FunctionRef::new(unsafe { LLVMGetBasicBlockParent(self.get_insert_block()) })
+    }
+
     pub fn set_insert_point(&mut self, block: LLVMBasicBlockRef) {
         unsafe {
             LLVMPositionBuilderAtEnd(self.inner, block);
         }
     }
 
+    pub fn set_insert_point_before(&mut self, instruction: &LLVMValue) {
+        unsafe {
+            LLVMPositionBuilderBefore(self.inner, instruction.ptr);
+        }
+    }
+
+    /// Moves the insertion point before the first instruction of `block`.
+    pub fn set_insert_point_at_start(&mut self, block: LLVMBasicBlockRef) {
+        unsafe {
+            let first = LLVMGetFirstInstruction(block);
+            if first.is_null() {
+                LLVMPositionBuilderAtEnd(self.inner, block);
+            } else {
+                LLVMPositionBuilderBefore(self.inner, first);
+            }
+        }
+    }
+
     pub fn create_ret(&mut self, value: &LLVMValue) -> LLVMValue {
         let ptr = unsafe { LLVMBuildRet(self.inner, value.ptr) };
         LLVMValue::new(ptr)
     }
+
+    /// Attaches `location` to the instructions built from now on, or nothing
+    /// if it is `None`.
+    pub fn set_debug_location(&mut self, context: &LLVMContext, location: Option<LLVMMetadataRef>) {
+        let location = match location {
+            Some(location) => unsafe { LLVMMetadataAsValue(context.inner, location) },
+            None => std::ptr::null_mut(),
+        };
+        unsafe {
+            LLVMSetCurrentDebugLocation(self.inner, location);
+        }
+    }
 }
 
 impl Drop for LLVMBuilder {
@@ -226,7 +650,159 @@ impl Drop for LLVMBuilder {
     }
 }
 
-pub struct PassManager {
+const DW_ATE_FLOAT: LLVMDWARFTypeEncoding = 0x04;
+
+/// Builds the DWARF description of a module compiled from one source file.
+pub(crate) struct DebugInfo {
+    builder: LLVMDIBuilderRef,
+    file: LLVMMetadataRef,
+    double_type: LLVMMetadataRef,
+}
+
+impl DebugInfo {
+    fn new(context: &mut LLVMContext, module: &mut LLVMModule, path: &Path) -> Self {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let directory = path
+            .parent()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let producer = "kaleidoscope";
+        let double = "double";
+        let version_key = "Debug Info Version";
+        unsafe {
+            let builder = LLVMCreateDIBuilder(module.inner);
+            let file = LLVMDIBuilderCreateFile(
+                builder,
+                name.as_ptr() as *const c_char,
+                name.len(),
+                directory.as_ptr() as *const c_char,
+                directory.len(),
+            );
+            LLVMDIBuilderCreateCompileUnit(
+                builder,
+                LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC,
+                file,
+                producer.as_ptr() as *const c_char,
+                producer.len(),
+                false as LLVMBool,
+                "".as_ptr() as *const c_char,
+                0,
+                0,
+                "".as_ptr() as *const c_char,
+                0,
+                LLVMDWARFEmissionKind::LLVMDWARFEmissionKindFull,
+                0,
+                false as LLVMBool,
+                false as LLVMBool,
+            );
+            let double_type = LLVMDIBuilderCreateBasicType(
+                builder,
+                double.as_ptr() as *const c_char,
+                double.len(),
+                64,
+                DW_ATE_FLOAT,
+                LLVMDIFlagZero,
+            );
+            // Without this flag, the debug info is dropped as outdated.
+            let version = LLVMConstInt(
+                LLVMInt32TypeInContext(context.inner),
+                LLVMDebugMetadataVersion().into(),
+                false as LLVMBool,
+            );
+            LLVMAddModuleFlag(
+                module.inner,
+                LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning,
+                version_key.as_ptr() as *const c_char,
+                version_key.len(),
+                LLVMValueAsMetadata(version),
+            );
+            Self {
+                builder,
+                file,
+                double_type,
+            }
+        }
+    }
+
+    /// Attaches a subprogram for `name`, defined at `line`, to `f` and
+    /// returns it.
+    fn create_function(&mut self, f: &FunctionRef, name: &str, line: usize) -> LLVMMetadataRef {
+        // The return type, then one double per argument.
+        let mut types = vec![self.double_type; f.num_args() + 1];
+        unsafe {
+            let ty = LLVMDIBuilderCreateSubroutineType(
+                self.builder,
+                self.file,
+                types.as_mut_ptr(),
+                types.len() as c_uint,
+                LLVMDIFlagZero,
+            );
+            let subprogram = LLVMDIBuilderCreateFunction(
+                self.builder,
+                self.file,
+                name.as_ptr() as *const c_char,
+                name.len(),
+                name.as_ptr() as *const c_char,
+                name.len(),
+                self.file,
+                line as c_uint,
+                ty,
+                false as LLVMBool,
+                true as LLVMBool,
+                line as c_uint,
+                LLVMDIFlagPrototyped,
+                false as LLVMBool,
+            );
+            LLVMSetSubprogram(f.ptr, subprogram);
+            subprogram
+        }
+    }
+
+    fn create_location(
+        &self,
+        context: &LLVMContext,
+        scope: LLVMMetadataRef,
+        (line, col): (usize, usize),
+    ) -> LLVMMetadataRef {
+        unsafe {
+            LLVMDIBuilderCreateDebugLocation(
+                context.inner,
+                line as c_uint,
+                col as c_uint,
+                scope,
+                std::ptr::null_mut(),
+            )
+        }
+    }
+
+    fn finalize_function(&mut self, subprogram: LLVMMetadataRef) {
+        unsafe {
+            LLVMDIBuilderFinalizeSubprogram(self.builder, subprogram);
+        }
+    }
+
+    /// Resolves what has been described so far, as the verifier and code
+    /// generation need. More functions may be described afterwards.
+    fn finalize(&self) {
+        unsafe {
+            LLVMDIBuilderFinalize(self.builder);
+        }
+    }
+}
+
+impl Drop for DebugInfo {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMDIBuilderFinalize(self.builder);
+            LLVMDisposeDIBuilder(self.builder);
+        }
+    }
+}
+
+pub(crate) struct PassManager {
     ptr: LLVMPassManagerRef,
 }
 
@@ -234,6 +810,7 @@ impl PassManager {
     fn new(module: &mut LLVMModule) -> Self {
         let ptr = unsafe {
             let ptr = LLVMCreateFunctionPassManagerForModule(module.inner);
+            LLVMAddPromoteMemoryToRegisterPass(ptr);
             LLVMAddInstructionCombiningPass(ptr);
             LLVMAddReassociatePass(ptr);
             LLVMAddGVNPass(ptr);
@@ -251,94 +828,800 @@ impl PassManager {
     }
 }
 
-pub struct IRGenerator {
-    context: LLVMContext,
-    module: LLVMModule,
-    builder: LLVMBuilder,
-    pass_manager: PassManager,
-    named_values: HashMap<String, LLVMValue>,
-}
-
-impl IRGenerator {
-    pub fn new() -> Self {
-        let mut context = LLVMContext::new();
-        let mut module = context.create_module("kaleidoscope");
-        let builder = LLVMBuilder::new(&mut context);
-        let pass_manager = PassManager::new(&mut module);
-        Self {
-            context,
-            module,
-            builder,
-            pass_manager,
-            named_values: HashMap::new(),
+impl Drop for PassManager {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMFinalizeFunctionPassManager(self.ptr);
+            LLVMDisposePassManager(self.ptr);
         }
     }
+}
 
-    pub fn gen(&mut self, ast: &ExprAST) -> Result<LLVMValue> {
-        match ast {
-            ExprAST::Number(value) => {
-                let value = unsafe { LLVMConstReal(self.context.get_double_type(), *value) };
-                Ok(LLVMValue::new(value))
-            }
-            ExprAST::Variable(name) => match self.named_values.get(name) {
-                Some(value) => Ok(LLVMValue::new(value.ptr)),
-                None => Err(LLVMError::VariableNotFound(name.clone())),
-            },
-            ExprAST::BinaryOp { op, lhs, rhs } => {
-                let lhs = self.gen(lhs)?;
-                let rhs = self.gen(rhs)?;
-                match op {
-                    Operator::LessThan => Ok(self.builder.create_fcmp(&lhs, &rhs)),
-                    Operator::Plus => Ok(self.builder.create_fadd(&lhs, &rhs)),
-                    Operator::Minus => Ok(self.builder.create_fsub(&lhs, &rhs)),
-                    Operator::Times => Ok(self.builder.create_fmul(&lhs, &rhs)),
-                }
-            }
-            ExprAST::Call { callee, args } => {
-                let callee_name = callee.clone();
-                let callee = self.module.get_function(&callee)?;
-                let num_args = callee.num_args();
-                if num_args != args.len() {
-                    return Err(LLVMError::InvalidArgumentsSize(callee_name, args.len()));
-                }
-                let mut values = Vec::with_capacity(num_args);
-                for arg in args {
-                    values.push(self.gen(arg)?);
-                }
-                Ok(self.builder.create_call(&callee, values))
-            }
-            ExprAST::Prototype(proto) => Ok(self.gen_proto(proto)?.into()),
-            ExprAST::Function { proto, body } => {
-                let mut f = match self.module.get_function(&proto.name) {
-                    Ok(f) => f,
-                    _ => self.gen_proto(proto)?,
-                };
-
-                let bb = self.context.create_basic_block(&f);
-                self.builder.set_insert_point(bb);
+fn initialize_native_target() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe {
+        LLVMLinkInMCJIT();
+        LLVM_InitializeNativeTarget();
+        LLVM_InitializeNativeAsmPrinter();
+    });
+}
 
-                self.named_values.clear();
-                for arg in f.args() {
-                    self.named_values.insert(arg.name(), arg);
-                }
+fn initialize_all_targets() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe {
+        LLVM_InitializeAllTargetInfos();
+        LLVM_InitializeAllTargets();
+        LLVM_InitializeAllTargetMCs();
+        LLVM_InitializeAllAsmPrinters();
+    });
+}
 
-                match self.gen(body) {
-                    Ok(body) => {
-                        self.builder.create_ret(&body);
-                        f.verify(LLVMVerifierFailureAction::LLVMPrintMessageAction);
-                        self.pass_manager.run_function_pass(&mut f);
-                        Ok(f.into())
-                    }
-                    Err(err) => {
-                        f.delete();
-                        Err(err)
-                    }
-                }
-            }
-        }
+fn take_message(message: *mut c_char) -> String {
+    unsafe {
+        let string = CStr::from_ptr(message).to_string_lossy().into_owned();
+        LLVMDisposeMessage(message);
+        string
+    }
+}
+
+pub(crate) struct ExecutionEngine {
+    inner: LLVMExecutionEngineRef,
+}
+
+impl ExecutionEngine {
+    /// Compiles a copy of `module`, which is owned and disposed by the engine,
+    /// so the original module can keep growing after this call.
+    pub fn new(module: &LLVMModule) -> Result<Self> {
+        initialize_native_target();
+        if let Some(name) = module.find_unresolved_extern() {
+            return Err(LLVMError::UnresolvedExtern(name));
+        }
+        let mut inner = std::ptr::null_mut();
+        let mut error = std::ptr::null_mut();
+        // The engine takes the copy even when it fails to be created: LLVM
+        // moves it into an `EngineBuilder`, which frees it with itself, so it
+        // must not be disposed here.
+        let failed = unsafe {
+            let module = LLVMCloneModule(module.inner);
+            LLVMCreateExecutionEngineForModule(&mut inner, module, &mut error)
+        };
+        if failed != 0 {
+            return Err(LLVMError::ExecutionEngine(take_message(error)));
+        }
+        Ok(Self { inner })
+    }
+
+    pub fn run_function(&self, name: &str) -> Result<f64> {
+        let c_name = CString::new(name).unwrap();
+        let address = unsafe { LLVMGetFunctionAddress(self.inner, c_name.as_ptr()) };
+        if address == 0 {
+            return Err(LLVMError::FunctionNotFound(name.to_string()));
+        }
+        let f: extern "C" fn() -> f64 = unsafe { std::mem::transmute(address as usize) };
+        Ok(f())
+    }
+}
+
+impl Drop for ExecutionEngine {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMDisposeExecutionEngine(self.inner);
+        }
+    }
+}
+
+/// The machine that object and assembly files are generated for. Fields left
+/// as `None` default to the host, or to a generic CPU for another triple.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TargetOptions {
+    pub triple: Option<String>,
+    pub cpu: Option<String>,
+    pub features: Option<String>,
+}
+
+pub(crate) struct TargetMachine {
+    inner: LLVMTargetMachineRef,
+    triple: CString,
+}
+
+impl TargetMachine {
+    pub fn new(options: &TargetOptions) -> Result<Self> {
+        let (triple, cpu, features) = match &options.triple {
+            Some(triple) => {
+                initialize_all_targets();
+                (triple.clone(), "generic".to_string(), String::new())
+            }
+            None => unsafe {
+                initialize_native_target();
+                (
+                    take_message(LLVMGetDefaultTargetTriple()),
+                    take_message(LLVMGetHostCPUName()),
+                    take_message(LLVMGetHostCPUFeatures()),
+                )
+            },
+        };
+        let triple = CString::new(triple).unwrap();
+        let cpu = CString::new(options.cpu.clone().unwrap_or(cpu)).unwrap();
+        let features = CString::new(options.features.clone().unwrap_or(features)).unwrap();
+
+        let mut target = std::ptr::null_mut();
+        let mut error = std::ptr::null_mut();
+        if unsafe { LLVMGetTargetFromTriple(triple.as_ptr(), &mut target, &mut error) } != 0 {
+            return Err(LLVMError::UnknownTarget(take_message(error)));
+        }
+        let inner = unsafe {
+            LLVMCreateTargetMachine(
+                target,
+                triple.as_ptr(),
+                cpu.as_ptr(),
+                features.as_ptr(),
+                LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+                LLVMRelocMode::LLVMRelocPIC,
+                LLVMCodeModel::LLVMCodeModelDefault,
+            )
+        };
+        Ok(Self { inner, triple })
+    }
+
+    /// Writes `module` for this target. The target's triple and data layout
+    /// are set on a copy, so `module` itself is left as it is.
+    pub fn emit_to_file(
+        &self,
+        module: &LLVMModule,
+        path: &Path,
+        file_type: LLVMCodeGenFileType,
+    ) -> Result<()> {
+        let path = CString::new(path.to_string_lossy().as_bytes()).unwrap();
+        let mut error = std::ptr::null_mut();
+        let module = LLVMModule {
+            inner: unsafe { LLVMCloneModule(module.inner) },
+        };
+        unsafe {
+            LLVMSetTarget(module.inner, self.triple.as_ptr());
+            let data_layout = LLVMCreateTargetDataLayout(self.inner);
+            let layout = LLVMCopyStringRepOfTargetData(data_layout);
+            LLVMSetDataLayout(module.inner, layout);
+            LLVMDisposeMessage(layout);
+            LLVMDisposeTargetData(data_layout);
+
+            if LLVMTargetMachineEmitToFile(
+                self.inner,
+                module.inner,
+                path.as_ptr() as *mut c_char,
+                file_type,
+                &mut error,
+            ) != 0
+            {
+                return Err(LLVMError::CodeGen(take_message(error)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TargetMachine {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMDisposeTargetMachine(self.inner);
+        }
+    }
+}
+
+fn check_prototype(f: &FunctionRef, proto: &Prototype) -> Result<()> {
+    if f.num_args() == proto.args.len() {
+        Ok(())
+    } else {
+        Err(LLVMError::PrototypeMismatch {
+            name: proto.name.clone(),
+            expected: f.num_args(),
+            found: proto.args.len(),
+        })
+    }
+}
+
+fn set_arg_names(f: &FunctionRef, proto: &Prototype) {
+    for (arg, name) in f.args().iter().zip(proto.args.iter()) {
+        let name = CString::new(name.as_str()).unwrap();
+        let len = name.as_bytes().len();
+        unsafe {
+            LLVMSetValueName2(arg.ptr, name.as_ptr(), len);
+        }
+    }
+}
+
+fn comparison_predicate(op: Operator) -> Option<LLVMRealPredicate> {
+    match op {
+        Operator::LessThan => Some(LLVMRealOLT),
+        Operator::GreaterThan => Some(LLVMRealOGT),
+        Operator::LessEqual => Some(LLVMRealOLE),
+        Operator::GreaterEqual => Some(LLVMRealOGE),
+        Operator::Equal => Some(LLVMRealOEQ),
+        Operator::NotEqual => Some(LLVMRealONE),
+        _ => None,
+    }
+}
+
+fn int_comparison_predicate(op: Operator) -> Option<LLVMIntPredicate> {
+    match op {
+        Operator::LessThan => Some(LLVMIntSLT),
+        Operator::GreaterThan => Some(LLVMIntSGT),
+        Operator::LessEqual => Some(LLVMIntSLE),
+        Operator::GreaterEqual => Some(LLVMIntSGE),
+        Operator::Equal => Some(LLVMIntEQ),
+        Operator::NotEqual => Some(LLVMIntNE),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NumericType {
+    Integer,
+    Double,
+}
+
+/// Decides the type a binary operation is computed in: integer arithmetic if
+/// both operands are integers, otherwise the integer side is promoted.
+/// Division always divides doubles, so `7 / 2` is `3.5` and a zero divisor
+/// gives `inf` or `nan` where `sdiv` would trap.
+fn binary_op_type(op: Operator, lhs: &LLVMValue, rhs: &LLVMValue) -> NumericType {
+    match op {
+        Operator::Divide => NumericType::Double,
+        _ if lhs.is_integer() && rhs.is_integer() => NumericType::Integer,
+        _ => NumericType::Double,
+    }
+}
+
+// Fields are dropped in declaration order. The pass manager and the debug info
+// builder refer to `module`, so they go first, and everything else here
+// belongs to `context`, so it has to come last.
+pub struct IRGenerator {
+    debug_info: Option<DebugInfo>,
+    pass_manager: PassManager,
+    builder: LLVMBuilder,
+    module: LLVMModule,
+    context: LLVMContext,
+    optimize: bool,
+    // Innermost scope last.
+    named_values: Vec<HashMap<String, LLVMValue>>,
+    // Where the node being generated starts. It is left at the failing node
+    // when generation fails.
+    span: Option<Span>,
+    // The debug info scope of the function being generated.
+    subprogram: Option<LLVMMetadataRef>,
+}
+
+impl Default for IRGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IRGenerator {
+    pub fn new() -> Self {
+        let mut context = LLVMContext::new();
+        let mut module = context.create_module("kaleidoscope");
+        let builder = LLVMBuilder::new(&mut context);
+        let pass_manager = PassManager::new(&mut module);
+        Self {
+            context,
+            module,
+            builder,
+            pass_manager,
+            debug_info: None,
+            optimize: false,
+            named_values: Vec::new(),
+            span: None,
+            subprogram: None,
+        }
+    }
+
+    /// Describes the functions generated from now on in DWARF, as compiled
+    /// from the file at `path`. Each function gets a subprogram, and each
+    /// instruction the position of the expression it was generated from.
+    pub fn enable_debug_info(&mut self, path: &Path) {
+        self.debug_info = Some(DebugInfo::new(&mut self.context, &mut self.module, path));
+    }
+
+    /// Creates a generator that runs the function passes over every function.
+    pub fn new_optimized() -> Self {
+        let mut generator = Self::new();
+        generator.optimize = true;
+        generator
+    }
+
+    /// Where the node whose generation failed last starts, if it was parsed.
+    pub fn error_span(&self) -> Option<Span> {
+        self.span
+    }
+
+    pub fn gen(&mut self, ast: &ExprAST) -> Result<LLVMValue> {
+        let parent = self.enter(ast);
+        let value = self.gen_kind(ast)?;
+        self.leave(ast, parent);
+        Ok(value)
+    }
+
+    /// Makes `ast` the node being generated, if it has a span, and returns
+    /// the span it replaces.
+    fn enter(&mut self, ast: &ExprAST) -> Option<Span> {
+        let parent = self.span;
+        if ast.span.is_some() {
+            self.span = ast.span;
+            self.set_debug_location();
+        }
+        parent
+    }
+
+    /// Goes back to `parent` once `ast` is generated.
+    fn leave(&mut self, ast: &ExprAST, parent: Option<Span>) {
+        if ast.span.is_some() {
+            self.span = parent;
+            self.set_debug_location();
+        }
+    }
+
+    /// Locates the instructions built from now on at the current node, if
+    /// debug info is enabled and a function is being generated.
+    fn set_debug_location(&mut self) {
+        let location = match (&self.debug_info, self.subprogram, self.span) {
+            (Some(debug_info), Some(scope), Some(Span { line, col })) => {
+                Some(debug_info.create_location(&self.context, scope, (line, col)))
+            }
+            _ => None,
+        };
+        self.builder.set_debug_location(&self.context, location);
+    }
+
+    fn gen_kind(&mut self, ast: &ExprAST) -> Result<LLVMValue> {
+        match &ast.kind {
+            ExprKind::Number(value) => Ok(self.context.const_double(*value)),
+            ExprKind::Integer(value) => Ok(self.context.const_i64(*value)),
+            // Strings are generated by the `Call` arm, the only place where
+            // one is allowed.
+            ExprKind::StringLiteral(_) => Err(LLVMError::MisplacedString),
+            ExprKind::Variable(name) => match self.lookup(name) {
+                Some(alloca) => Ok(self.builder.create_load(&alloca, name)),
+                None => Err(LLVMError::VariableNotFound(name.clone())),
+            },
+            ExprKind::BinaryOp {
+                op: Operator::Assign,
+                lhs,
+                rhs,
+            } => {
+                let name = match &lhs.kind {
+                    ExprKind::Variable(name) => name,
+                    _ => return Err(LLVMError::InvalidAssignment),
+                };
+                let value = self.gen(rhs)?;
+                let value = self.promote(value);
+                match self.lookup(name) {
+                    Some(alloca) => {
+                        self.builder.create_store(&value, &alloca);
+                        Ok(value)
+                    }
+                    None => Err(LLVMError::VariableNotFound(name.clone())),
+                }
+            }
+            ExprKind::BinaryOp { op, .. } if comparison_predicate(*op).is_some() => {
+                let cond = self.gen_condition(ast)?;
+                Ok(self.builder.create_ui_to_fp(&cond))
+            }
+            ExprKind::BinaryOp {
+                op: op @ Operator::Custom(_),
+                lhs,
+                rhs,
+            } => {
+                let lhs = self.gen(lhs)?;
+                let rhs = self.gen(rhs)?;
+                let args = vec![self.promote(lhs), self.promote(rhs)];
+                let f = self.module.get_function(&format!("binary{}", op))?;
+                Ok(self.builder.create_call(&f, args))
+            }
+            ExprKind::BinaryOp {
+                op: Operator::Power,
+                lhs,
+                rhs,
+            } => {
+                let lhs = self.gen(lhs)?;
+                let rhs = self.gen(rhs)?;
+                let args = vec![self.promote(lhs), self.promote(rhs)];
+                let f = self.pow_intrinsic();
+                Ok(self.builder.create_call(&f, args))
+            }
+            ExprKind::BinaryOp { op, lhs, rhs } => {
+                let lhs = self.gen(lhs)?;
+                let rhs = self.gen(rhs)?;
+                let ty = binary_op_type(*op, &lhs, &rhs);
+                let (lhs, rhs) = (self.promote_to(lhs, ty), self.promote_to(rhs, ty));
+                Ok(match (op, ty) {
+                    (Operator::Plus, NumericType::Integer) => self.builder.create_add(&lhs, &rhs),
+                    (Operator::Minus, NumericType::Integer) => self.builder.create_sub(&lhs, &rhs),
+                    (Operator::Times, NumericType::Integer) => self.builder.create_mul(&lhs, &rhs),
+                    (Operator::Plus, NumericType::Double) => self.builder.create_fadd(&lhs, &rhs),
+                    (Operator::Minus, NumericType::Double) => self.builder.create_fsub(&lhs, &rhs),
+                    (Operator::Times, NumericType::Double) => self.builder.create_fmul(&lhs, &rhs),
+                    (Operator::Divide, NumericType::Double) => self.builder.create_fdiv(&lhs, &rhs),
+                    // Handled by the arms above.
+                    _ => unreachable!(),
+                })
+            }
+            ExprKind::UnaryOp { op, operand } => {
+                let operand = self.gen(operand)?;
+                match self.module.get_function(&format!("unary{}", op)) {
+                    Ok(f) => {
+                        let operand = self.promote(operand);
+                        Ok(self.builder.create_call(&f, vec![operand]))
+                    }
+                    // Negation is builtin unless the user defines `unary-`.
+                    Err(_) if *op == Operator::Minus && operand.is_integer() => {
+                        Ok(self.builder.create_neg(&operand))
+                    }
+                    Err(_) if *op == Operator::Minus => Ok(self.builder.create_fneg(&operand)),
+                    Err(err) => Err(err),
+                }
+            }
+            ExprKind::Call { callee, args } => {
+                let callee_name = callee.clone();
+                let callee = self.module.get_function(&callee)?;
+                let num_args = callee.num_args();
+                if num_args != args.len() {
+                    return Err(LLVMError::InvalidArgumentsSize(
+                        callee_name,
+                        args.len(),
+                        num_args,
+                    ));
+                }
+                let mut values = Vec::with_capacity(num_args);
+                for arg in args {
+                    let value = match &arg.kind {
+                        ExprKind::StringLiteral(value) => self.builder.create_global_string(value),
+                        _ => {
+                            let value = self.gen(arg)?;
+                            self.promote(value)
+                        }
+                    };
+                    values.push(value);
+                }
+                Ok(self.builder.create_call(&callee, values))
+            }
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let cond = self.gen_condition(cond)?;
+
+                let f = self.builder.get_insert_function();
+                let then_block = self.context.append_basic_block(&f, "then");
+                let else_block = self.context.append_basic_block(&f, "else");
+                let merge_block = self.context.append_basic_block(&f, "ifcont");
+                self.builder.create_cond_br(&cond, then_block, else_block);
+
+                self.builder.set_insert_point(then_block);
+                let mut then_value = self.gen(then_branch)?;
+                let then_br = self.builder.create_br(merge_block);
+                // Codegen of the branch may have moved the insertion point
+                // (e.g. a nested `if`), so the phi must refer to the current one.
+                let then_block = self.builder.get_insert_block();
+
+                self.builder.set_insert_point(else_block);
+                let mut else_value = self.gen(else_branch)?;
+                let else_br = self.builder.create_br(merge_block);
+                let else_block = self.builder.get_insert_block();
+
+                // An integer branch meeting a double one is promoted in its
+                // own block.
+                if then_value.is_integer() != else_value.is_integer() {
+                    self.builder.set_insert_point_before(&then_br);
+                    then_value = self.promote(then_value);
+                    self.builder.set_insert_point_before(&else_br);
+                    else_value = self.promote(else_value);
+                }
+
+                self.builder.set_insert_point(merge_block);
+                Ok(self.builder.create_phi(
+                    "iftmp",
+                    &[(&then_value, then_block), (&else_value, else_block)],
+                ))
+            }
+            ExprKind::For {
+                var_name,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let f = self.builder.get_insert_function();
+                let alloca = self.create_entry_block_alloca(&f, var_name);
+                let start = self.gen(start)?;
+                let start = self.promote(start);
+                self.builder.create_store(&start, &alloca);
+
+                let loop_block = self.context.append_basic_block(&f, "loop");
+                self.builder.create_br(loop_block);
+                self.builder.set_insert_point(loop_block);
+
+                // The loop variable shadows any existing binding of the same
+                // name until the loop ends.
+                self.push_scope();
+                self.insert(var_name.clone(), alloca);
+
+                self.gen(body)?;
+
+                let step = match step {
+                    Some(step) => self.gen(step)?,
+                    None => self.context.const_double(1.0),
+                };
+                let step = self.promote(step);
+                let end = self.gen_condition(end)?;
+
+                let alloca = self.lookup(var_name).unwrap();
+                let variable = self.builder.create_load(&alloca, var_name);
+                let next_variable = self.builder.create_fadd(&variable, &step);
+                self.builder.create_store(&next_variable, &alloca);
+
+                let after_block = self.context.append_basic_block(&f, "afterloop");
+                self.builder.create_cond_br(&end, loop_block, after_block);
+                self.builder.set_insert_point(after_block);
+                self.pop_scope();
+
+                Ok(self.context.const_double(0.0))
+            }
+            ExprKind::VarIn { bindings, body } => {
+                let f = self.builder.get_insert_function();
+                self.push_scope();
+                for (name, init) in bindings {
+                    // The initializer is evaluated before the variable is in
+                    // scope, so `var a = a in ...` refers to the outer `a`.
+                    let init = match init {
+                        Some(init) => self.gen(init)?,
+                        None => self.context.const_double(0.0),
+                    };
+                    let init = self.promote(init);
+                    let alloca = self.create_entry_block_alloca(&f, name);
+                    self.builder.create_store(&init, &alloca);
+                    self.insert(name.clone(), alloca);
+                }
+
+                let body = self.gen(body)?;
+                self.pop_scope();
+                Ok(body)
+            }
+            ExprKind::Prototype(proto) => Ok(self.gen_proto(proto)?.into()),
+            ExprKind::Function { proto, body } => {
+                // The body is built in a new function that replaces any
+                // earlier declaration or definition once it verifies. Callers
+                // of the old one are pointed at the new one, and nothing
+                // changes if codegen fails. Redefining a function is handy in
+                // the REPL, and a declaration may come from `extern` or
+                // `gen_all`.
+                let old = self.module.get_function(&proto.name).ok();
+                if let Some(old) = &old {
+                    check_prototype(old, proto)?;
+                }
+                let mut f = self.add_proto(proto);
+
+                let bb = self.context.append_basic_block(&f, "entry");
+                self.builder.set_insert_point(bb);
+
+                self.subprogram = None;
+                if let Some(debug_info) = &mut self.debug_info {
+                    let line = self.span.map_or(0, |span| span.line);
+                    self.subprogram = Some(debug_info.create_function(&f, &proto.name, line));
+                }
+                let subprogram = self.subprogram;
+                self.set_debug_location();
+
+                // Drop any scopes left behind by a previous failed function.
+                self.named_values.clear();
+                self.push_scope();
+                for arg in f.args() {
+                    let name = arg.name();
+                    let alloca = self.create_entry_block_alloca(&f, &name);
+                    self.builder.create_store(&arg, &alloca);
+                    self.insert(name, alloca);
+                }
+
+                let result = self.gen(body);
+                // The return keeps the location of the function, and nothing
+                // built after it is located in its scope.
+                self.subprogram = None;
+                match result {
+                    Ok(body) => {
+                        let body = self.promote(body);
+                        self.builder.create_ret(&body);
+                        if let (Some(debug_info), Some(subprogram)) =
+                            (&mut self.debug_info, subprogram)
+                        {
+                            debug_info.finalize_function(subprogram);
+                        }
+                        // Only the new function is checked, so a definition
+                        // costs the same however large the module grows.
+                        if !f.verify() {
+                            // LLVMVerifyFunction gives no details, but the
+                            // module's verifier does.
+                            let err = self.verify_module().err().unwrap_or_else(|| {
+                                LLVMError::VerificationFailed(format!(
+                                    "invalid function '{}'",
+                                    proto.name
+                                ))
+                            });
+                            f.delete();
+                            return Err(err);
+                        }
+                        if let Some(old) = old {
+                            old.replace_with(&f);
+                        }
+                        if self.optimize {
+                            self.pass_manager.run_function_pass(&mut f);
+                        }
+                        Ok(f.into())
+                    }
+                    Err(err) => {
+                        f.delete();
+                        Err(err)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Promotes an integer to a double. Other values are returned as is.
+    fn promote(&mut self, value: LLVMValue) -> LLVMValue {
+        if value.is_integer() {
+            self.builder.create_si_to_fp(&value)
+        } else {
+            value
+        }
+    }
+
+    fn promote_to(&mut self, value: LLVMValue, ty: NumericType) -> LLVMValue {
+        match ty {
+            NumericType::Integer => value,
+            NumericType::Double => self.promote(value),
+        }
+    }
+
+    fn push_scope(&mut self) {
+        self.named_values.push(HashMap::new());
+    }
+
+    fn pop_scope(&mut self) {
+        self.named_values.pop();
+    }
+
+    /// Finds the innermost binding of `name`.
+    fn lookup(&self, name: &str) -> Option<LLVMValue> {
+        self.named_values
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .cloned()
+    }
+
+    fn insert(&mut self, name: String, value: LLVMValue) {
+        self.named_values
+            .last_mut()
+            .expect("no scope to insert into")
+            .insert(name, value);
+    }
+
+    fn create_entry_block_alloca(&mut self, f: &FunctionRef, name: &str) -> LLVMValue {
+        let mut builder = LLVMBuilder::new(&mut self.context);
+        builder.set_insert_point_at_start(f.entry_block());
+        builder.create_alloca(name)
+    }
+
+    /// Generates `ast` as an `i1` truth value. Comparisons yield their `fcmp`
+    /// directly instead of round-tripping through a double.
+    fn gen_condition(&mut self, ast: &ExprAST) -> Result<LLVMValue> {
+        if let ExprKind::BinaryOp { op, lhs, rhs } = &ast.kind {
+            if let Some(pred) = comparison_predicate(*op) {
+                let parent = self.enter(ast);
+                let lhs = self.gen(lhs)?;
+                let rhs = self.gen(rhs)?;
+                let cond = if binary_op_type(*op, &lhs, &rhs) == NumericType::Integer {
+                    let pred = int_comparison_predicate(*op).unwrap();
+                    self.builder.create_icmp(pred, &lhs, &rhs)
+                } else {
+                    let (lhs, rhs) = (self.promote(lhs), self.promote(rhs));
+                    self.builder.create_fcmp(pred, &lhs, &rhs)
+                };
+                self.leave(ast, parent);
+                return Ok(cond);
+            }
+        }
+        let value = self.gen(ast)?;
+        Ok(self.builder.create_to_bool(&value))
+    }
+
+    /// Generates `asts` in order after declaring every function they define,
+    /// so that a function can call another defined later on.
+    pub fn gen_all(&mut self, asts: &[ExprAST]) -> Result<()> {
+        for ast in asts {
+            if let ExprKind::Function { proto, .. } = &ast.kind {
+                if !proto.is_anonymous() {
+                    self.span = ast.span;
+                    self.gen_proto(proto)?;
+                }
+            }
+        }
+        self.span = None;
+        for ast in asts {
+            self.gen(ast)?;
+        }
+        Ok(())
+    }
+
+    pub fn eval_top_level(&mut self, ast: &ExprAST) -> Result<f64> {
+        let name = match &ast.kind {
+            ExprKind::Function { proto, .. } if proto.is_anonymous() => proto.name.clone(),
+            _ => return Err(LLVMError::NotAnExpression),
+        };
+        self.span = None;
+        self.gen(ast)?;
+        let f = self.module.get_function(&name)?;
+        // Constant expressions need no JIT.
+        let result = match f.constant_result() {
+            Some(value) => Ok(value),
+            None => {
+                self.finalize_debug_info();
+                ExecutionEngine::new(&self.module).and_then(|engine| engine.run_function(&name))
+            }
+        };
+        // Remove the anonymous function so the next expression can reuse its name.
+        f.delete();
+        result
+    }
+
+    /// Makes a native function callable from JIT-compiled code under `name`.
+    ///
+    /// Functions of the host process, such as libc's math functions, resolve
+    /// without registration: after `extern atan2(y x);`, `atan2(1, 1);` calls
+    /// the C `atan2`. The function at `addr` must be a non-variadic
+    /// `extern "C" fn` returning `f64`, with an `f64` parameter for each
+    /// argument of the `extern` declaration, or a `*const c_char` where calls
+    /// pass a string literal.
+    pub fn register_extern(&mut self, name: &str, addr: *const ()) {
+        let name = CString::new(name).unwrap();
+        unsafe {
+            LLVMAddSymbol(name.as_ptr(), addr as *mut c_void);
+        }
+    }
+
+    /// Returns the declaration of `llvm.pow.f64`, adding it on first use.
+    fn pow_intrinsic(&mut self) -> FunctionRef {
+        const NAME: &str = "llvm.pow.f64";
+        if let Ok(f) = self.module.get_function(NAME) {
+            return f;
+        }
+        let double = self.context.get_double_type();
+        let mut params = [double, double];
+        let f_type = unsafe {
+            LLVMFunctionType(
+                double,
+                params.as_mut_ptr(),
+                params.len() as c_uint,
+                false as LLVMBool,
+            )
+        };
+        self.module.add_function(NAME, f_type)
     }
 
     pub fn gen_proto(&mut self, proto: &Prototype) -> Result<FunctionRef> {
+        match self.module.get_function(&proto.name) {
+            // Reuse the earlier declaration with the latest parameter names.
+            Ok(f) => {
+                check_prototype(&f, proto)?;
+                set_arg_names(&f, proto);
+                Ok(f)
+            }
+            Err(_) => Ok(self.add_proto(proto)),
+        }
+    }
+
+    /// Adds a new function for `proto`. LLVM renames it if the name is taken.
+    fn add_proto(&mut self, proto: &Prototype) -> FunctionRef {
         let mut doubles = vec![self.context.get_double_type(); proto.args.len()];
         let num_args = doubles.len();
         let f_type = unsafe {
@@ -349,21 +1632,981 @@ impl IRGenerator {
                 false as LLVMBool,
             )
         };
-
         let f = self.module.add_function(&proto.name, f_type);
-        for (arg, name) in f.args().iter().zip(proto.args.iter()) {
-            let name = CString::new(name.as_str()).unwrap();
-            let len = name.as_bytes().len();
-            unsafe {
-                LLVMSetValueName2(arg.ptr, name.as_ptr(), len);
+        set_arg_names(&f, proto);
+        f
+    }
+
+    /// Adds a C `main` that calls the last top-level expression, if any, and
+    /// returns 0, so that an object file links into an executable. A `main`
+    /// defined by the program is left as it is.
+    pub fn add_entry_point(&mut self) -> Result<()> {
+        if self.module.get_function("main").is_ok() {
+            return Ok(());
+        }
+        let f_type = unsafe {
+            LLVMFunctionType(
+                self.context.get_i64_type(),
+                std::ptr::null_mut(),
+                0,
+                false as LLVMBool,
+            )
+        };
+        let main = self.module.add_function("main", f_type);
+        let bb = self.context.append_basic_block(&main, "entry");
+        self.builder.set_insert_point(bb);
+        // `main` has no source, and so no subprogram to locate calls in.
+        self.builder.set_debug_location(&self.context, None);
+        // Each top-level expression replaces the previous one, so this is
+        // the last in the program.
+        if let Ok(expr) = self.module.get_function(&Prototype::anonymous().name) {
+            if !expr.is_declaration() {
+                self.builder.create_call(&expr, Vec::new());
             }
         }
-        Ok(f)
+        let zero = self.context.const_i64(0);
+        self.builder.create_ret(&zero);
+        self.verify_module()
+    }
+
+    /// Finalizes the debug info, if any, before the module is verified or
+    /// written.
+    fn finalize_debug_info(&self) {
+        if let Some(debug_info) = &self.debug_info {
+            debug_info.finalize();
+        }
+    }
+
+    pub fn write_object_file<P: AsRef<Path>>(&self, path: P, target: &TargetOptions) -> Result<()> {
+        self.finalize_debug_info();
+        let machine = TargetMachine::new(target)?;
+        machine.emit_to_file(
+            &self.module,
+            path.as_ref(),
+            LLVMCodeGenFileType::LLVMObjectFile,
+        )
+    }
+
+    pub fn write_assembly_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        target: &TargetOptions,
+    ) -> Result<()> {
+        self.finalize_debug_info();
+        let machine = TargetMachine::new(target)?;
+        machine.emit_to_file(
+            &self.module,
+            path.as_ref(),
+            LLVMCodeGenFileType::LLVMAssemblyFile,
+        )
+    }
+
+    pub fn write_llvm_ir<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.finalize_debug_info();
+        let path = CString::new(path.as_ref().to_string_lossy().as_bytes()).unwrap();
+        let mut error = std::ptr::null_mut();
+        if unsafe { LLVMPrintModuleToFile(self.module.inner, path.as_ptr(), &mut error) } != 0 {
+            return Err(LLVMError::CodeGen(take_message(error)));
+        }
+        Ok(())
+    }
+
+    pub fn write_bitcode<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.finalize_debug_info();
+        let path = path.as_ref().to_string_lossy();
+        let c_path = CString::new(path.as_bytes()).unwrap();
+        if unsafe { LLVMWriteBitcodeToFile(self.module.inner, c_path.as_ptr()) } != 0 {
+            return Err(LLVMError::WriteBitcode(path.into_owned()));
+        }
+        Ok(())
+    }
+
+    pub fn verify_module(&self) -> Result<()> {
+        self.finalize_debug_info();
+        let mut message = std::ptr::null_mut();
+        let failed = unsafe {
+            LLVMVerifyModule(
+                self.module.inner,
+                LLVMVerifierFailureAction::LLVMReturnStatusAction,
+                &mut message,
+            )
+        };
+        // The message is allocated even when the module is valid.
+        let message = take_message(message);
+        if failed != 0 {
+            return Err(LLVMError::VerificationFailed(message));
+        }
+        Ok(())
+    }
+
+    pub fn module_to_string(&self) -> String {
+        take_message(unsafe { LLVMPrintModuleToString(self.module.inner) })
     }
 
     pub fn dump_module(&self) {
-        unsafe {
-            LLVMDumpModule(self.module.inner);
+        eprint!("{}", self.module_to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::Parser;
+
+    fn parse(input: &str) -> ExprAST {
+        let tokens = tokenize(input).unwrap();
+        Parser::new(tokens.into_iter()).parse().unwrap()
+    }
+
+    #[test]
+    fn test_error_messages() {
+        let mut generator = IRGenerator::new();
+        let message = |generator: &mut IRGenerator, input| {
+            generator.gen(&parse(input)).unwrap_err().to_string()
+        };
+        assert_eq!(
+            message(&mut generator, "def f(x) y;"),
+            "variable 'y' not found"
+        );
+        assert_eq!(
+            message(&mut generator, "def f(x) g(x);"),
+            "function 'g' not found"
+        );
+        generator.gen(&parse("def add(a b) a + b;")).unwrap();
+        assert_eq!(
+            message(&mut generator, "def f(x) add(x, x, x);"),
+            "function 'add' expects 2 arguments but got 3"
+        );
+        assert_eq!(
+            message(&mut generator, "def f(x) 1 = x;"),
+            "the left-hand side of '=' must be a variable"
+        );
+    }
+
+    #[test]
+    fn test_string_literal() {
+        extern "C" fn length(s: *const c_char) -> f64 {
+            unsafe { CStr::from_ptr(s) }.to_bytes().len() as f64
         }
+
+        let mut generator = IRGenerator::new();
+        generator.register_extern("testlength", length as *const ());
+        generator.gen(&parse("extern testlength(s);")).unwrap();
+        let ast = parse(r#"testlength("a\tb\n") + testlength("");"#);
+        assert_eq!(generator.eval_top_level(&ast), Ok(4.0));
+        assert!(generator.module_to_string().contains(r#"c"a\09b\0A\00""#));
+
+        // The string ends at the first NUL for C, but is generated whole.
+        let ast = parse("testlength(\"a\0b\");");
+        assert_eq!(generator.eval_top_level(&ast), Ok(1.0));
+        assert!(generator.module_to_string().contains(r#"c"a\00b\00""#));
+        assert_eq!(generator.verify_module(), Ok(()));
+    }
+
+    #[test]
+    fn test_misplaced_string() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("extern f(x);")).unwrap();
+        for input in &[
+            r#""a";"#,
+            r#"def g(x) "a";"#,
+            r#"if "a" then 1 else 2;"#,
+            r#"if 1 then "a" else 2;"#,
+            r#"1 + "a";"#,
+            r#""a" < 1;"#,
+            r#"-"a";"#,
+            r#"var x = "a" in x;"#,
+            r#"var x = 1 in x = "a";"#,
+            r#"for i = "a", i < 2 in 0;"#,
+            r#"f(("a") + 1);"#,
+        ] {
+            assert_eq!(
+                generator.gen(&parse(input)).map(|_| ()),
+                Err(LLVMError::MisplacedString),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(generator.verify_module(), Ok(()));
+        // Only a string passed to a call is generated.
+        assert!(generator.gen(&parse(r#"def g(x) f("a");"#)).is_ok());
+    }
+
+    #[test]
+    fn test_division() {
+        let mut generator = IRGenerator::new();
+        let f = generator
+            .gen(&parse("def f(a b) a / b;"))
+            .unwrap()
+            .to_string();
+        assert!(f.contains("%divtmp = fdiv double"), "{}", f);
+        assert_eq!(generator.verify_module(), Ok(()));
+        assert_eq!(generator.eval_top_level(&parse("f(3, 4);")), Ok(0.75));
+    }
+
+    #[test]
+    fn test_power() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("def f(x y) x ^ y;")).unwrap();
+        generator.gen(&parse("def g(x) x ^ 2;")).unwrap();
+        let ir = generator.module_to_string();
+        assert!(ir.contains("call double @llvm.pow.f64"));
+        // The intrinsic is declared only once.
+        assert_eq!(ir.matches("declare double @llvm.pow.f64").count(), 1);
+        assert_eq!(generator.eval_top_level(&parse("2 ^ 3 ^ 2;")), Ok(512.0));
+        assert_eq!(generator.eval_top_level(&parse("f(3, 2) * 2;")), Ok(18.0));
+    }
+
+    #[test]
+    fn test_integers() {
+        let mut generator = IRGenerator::new();
+        let mut eval = |input| generator.eval_top_level(&parse(input));
+        assert_eq!(eval("7 / 2;"), Ok(3.5));
+        assert_eq!(eval("7 / 2.0;"), Ok(3.5));
+        assert_eq!(eval("-(7 / 2) * 2;"), Ok(-7.0));
+        assert_eq!(eval("-(7 - 2) * 2;"), Ok(-10.0));
+        assert_eq!(eval("if 0 then 1 else 0.5;"), Ok(0.5));
+        assert_eq!(eval("var x = 3 in x / 2;"), Ok(1.5));
+        assert_eq!(eval("1 / 0;"), Ok(f64::INFINITY));
+        assert_eq!(eval("(if 1 then 3 else 4) / 0;"), Ok(f64::INFINITY));
+        assert_eq!(eval("(if 1 then 3 else 4) / 2;"), Ok(1.5));
+
+        let f = generator
+            .gen(&parse("def f(x) (if x then 3 else 4) < 4;"))
+            .unwrap()
+            .to_string();
+        assert!(f.contains("phi i64 [ 3, %then ], [ 4, %else ]"), "{}", f);
+        assert!(f.contains("icmp slt i64"), "{}", f);
+        let h = generator
+            .gen(&parse(
+                "def h(x) (if x then 3 else 4) / (if x then 0 else 1);",
+            ))
+            .unwrap()
+            .to_string();
+        assert!(!h.contains("sdiv"), "{}", h);
+
+        // The integer branch is converted before it reaches the phi.
+        let g = generator
+            .gen(&parse("def g(x) if x then 1 else x;"))
+            .unwrap()
+            .to_string();
+        assert!(
+            g.contains("phi double [ 1.000000e+00, %then ], [ %x"),
+            "{}",
+            g
+        );
+        assert_eq!(generator.verify_module(), Ok(()));
+        assert_eq!(generator.eval_top_level(&parse("f(1) + g(0.5);")), Ok(2.0));
+    }
+
+    #[test]
+    fn test_redefinition() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("def f(x) x;")).unwrap();
+        generator.gen(&parse("def g(x) f(x) * 2;")).unwrap();
+        generator.gen(&parse("def f(y) y + 1;")).unwrap();
+        let ir = generator.module_to_string();
+        assert_eq!(ir.matches("define double @f(").count(), 1, "{}", ir);
+        assert!(!ir.contains("@f."), "{}", ir);
+        // Existing callers use the new definition.
+        assert_eq!(generator.eval_top_level(&parse("g(2);")), Ok(6.0));
+
+        // A failed redefinition leaves the current one intact.
+        assert!(generator.gen(&parse("def f(y) z;")).is_err());
+        assert_eq!(generator.eval_top_level(&parse("f(2);")), Ok(3.0));
+        assert!(matches!(
+            generator.gen(&parse("def f(a b) a;")),
+            Err(LLVMError::PrototypeMismatch { .. })
+        ));
+        assert_eq!(generator.verify_module(), Ok(()));
+
+        generator.gen(&parse("extern g(x);")).unwrap();
+        generator.gen(&parse("def g(x) x * 3;")).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("g(2);")), Ok(6.0));
+    }
+
+    #[test]
+    fn test_add_entry_point() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("def f(x) x * 2;")).unwrap();
+        generator.gen(&parse("f(1);")).unwrap();
+        generator.gen(&parse("f(21);")).unwrap();
+        generator.add_entry_point().unwrap();
+        let ir = generator.module_to_string();
+        assert!(ir.contains("define i64 @main()"), "{}", ir);
+        assert!(ir.contains("call double @__anon_expr()"), "{}", ir);
+        assert!(ir.contains("f(double 2.100000e+01)"), "{}", ir);
+        assert!(!ir.contains("1.000000e+00"), "{}", ir);
+
+        // Without a top-level expression, main just returns.
+        let mut generator = IRGenerator::new();
+        generator.add_entry_point().unwrap();
+        let ir = generator.module_to_string();
+        assert!(ir.contains("ret i64 0"), "{}", ir);
+
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("def main() 1;")).unwrap();
+        generator.add_entry_point().unwrap();
+        let ir = generator.module_to_string();
+        assert!(ir.contains("define double @main()"), "{}", ir);
+        assert!(!ir.contains("i64 @main"), "{}", ir);
+    }
+
+    #[test]
+    fn test_gen_all_forward_reference() {
+        let tokens = tokenize(
+            "def isEven(n) if n == 0 then 1 else isOdd(n - 1);
+             def isOdd(n) if n == 0 then 0 else isEven(n - 1);",
+        )
+        .unwrap();
+        let asts = Parser::new(tokens.into_iter()).parse_all().unwrap();
+        let mut generator = IRGenerator::new();
+        generator.gen_all(&asts).unwrap();
+        assert_eq!(generator.verify_module(), Ok(()));
+        assert_eq!(generator.eval_top_level(&parse("isEven(10);")), Ok(1.0));
+        assert_eq!(generator.eval_top_level(&parse("isOdd(7);")), Ok(1.0));
+        assert_eq!(generator.eval_top_level(&parse("isEven(7);")), Ok(0.0));
+
+        // Statement by statement, the call comes before the callee exists.
+        let mut generator = IRGenerator::new();
+        assert_eq!(
+            generator.gen(&asts[0]),
+            Err(LLVMError::FunctionNotFound("isOdd".to_string()))
+        );
+
+        // A failing body leaves the forward declaration its callers use.
+        let tokens = tokenize("def a(x) b(x); def b(y) z;").unwrap();
+        let asts = Parser::new(tokens.into_iter()).parse_all().unwrap();
+        let mut generator = IRGenerator::new();
+        assert_eq!(
+            generator.gen_all(&asts),
+            Err(LLVMError::VariableNotFound("z".to_string()))
+        );
+        assert_eq!(generator.error_span(), Some(Span { line: 1, col: 25 }));
+        assert_eq!(generator.verify_module(), Ok(()));
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let tokens = tokenize(
+            "# Fibonacci numbers\n\
+             def fib(x)\n\
+             \x20 if x < 3 then\n\
+             \x20   1\n\
+             \x20 else\n\
+             \x20   fib(x - 1) + fib(x - 2);\n\
+             \n  fib(10);\n",
+        )
+        .unwrap();
+        let asts = Parser::new(tokens.into_iter()).parse_all().unwrap();
+        let mut generator = IRGenerator::new_optimized();
+        generator.enable_debug_info(Path::new("examples/fib.k"));
+        generator.gen_all(&asts).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("fib(10);")), Ok(55.0));
+        generator.add_entry_point().unwrap();
+        assert_eq!(generator.verify_module(), Ok(()));
+
+        let ir = generator.module_to_string();
+        for expected in [
+            r#"!DIFile(filename: "fib.k", directory: "examples")"#,
+            r#"!DISubprogram(name: "fib", linkageName: "fib", scope: !"#,
+            "!DILocation(line: 2, column: 1,",
+            r#"!"Debug Info Version""#,
+        ] {
+            assert!(ir.contains(expected), "{}\n{}", expected, ir);
+        }
+        // Each expression is located on its own line, so a debugger can
+        // step through them: the comparison, and both recursive calls.
+        for expected in [
+            "!DILocation(line: 3, column: 8,",
+            "!DILocation(line: 6, column: 5,",
+            "!DILocation(line: 6, column: 18,",
+        ] {
+            assert!(ir.contains(expected), "{}\n{}", expected, ir);
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("kaleidoscope-{}-debug-info.o", std::process::id()));
+        generator
+            .write_object_file(&path, &TargetOptions::default())
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Functions can still be described once the debug info is finalized.
+        generator.gen(&parse("def g(x)\n  fib(x) + 1;")).unwrap();
+        assert_eq!(generator.verify_module(), Ok(()));
+        let ir = generator.module_to_string();
+        assert!(ir.contains(r#"!DISubprogram(name: "g""#), "{}", ir);
+        assert!(ir.contains("!DILocation(line: 2, column: 3,"), "{}", ir);
+
+        // Nothing is described unless asked for.
+        let mut generator = IRGenerator::new();
+        generator.gen_all(&asts).unwrap();
+        assert!(!generator.module_to_string().contains("!dbg"));
+    }
+
+    #[test]
+    fn test_function_ref() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("extern sin(x);")).unwrap();
+        generator.gen(&parse("def f(x) x;")).unwrap();
+        let sin = generator.module.get_function("sin").unwrap();
+        assert_eq!(sin.name(), "sin");
+        assert!(sin.is_declaration());
+        let f = generator.module.get_function("f").unwrap();
+        assert_eq!(f.name(), "f");
+        assert!(!f.is_declaration());
+    }
+
+    #[test]
+    fn test_prototype_mismatch() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("extern foo(x y);")).unwrap();
+        assert_eq!(
+            generator.gen(&parse("def foo(a) a;")),
+            Err(LLVMError::PrototypeMismatch {
+                name: "foo".to_string(),
+                expected: 2,
+                found: 1,
+            })
+        );
+        assert_eq!(
+            generator.gen(&parse("extern foo(x);")),
+            Err(LLVMError::PrototypeMismatch {
+                name: "foo".to_string(),
+                expected: 2,
+                found: 1,
+            })
+        );
+        // A matching definition binds its own parameter names.
+        generator.gen(&parse("extern foo(x y);")).unwrap();
+        generator.gen(&parse("def foo(a b) a - b;")).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("foo(5, 3);")), Ok(2.0));
+    }
+
+    #[test]
+    fn test_drop() {
+        // Dropping a generator disposes the pass manager, builder, module and
+        // context in that order, after both optimised and plain codegen.
+        for mut generator in [IRGenerator::new(), IRGenerator::new_optimized()] {
+            generator.gen(&parse("def f(x) x * 2;")).unwrap();
+            assert_eq!(generator.eval_top_level(&parse("f(2);")), Ok(4.0));
+        }
+    }
+
+    #[test]
+    fn test_cached_double_type() {
+        let context = LLVMContext::new();
+        let ty = unsafe { LLVMDoubleTypeInContext(context.inner) };
+        assert_eq!(context.get_double_type(), ty);
+        assert_eq!(context.get_double_type(), context.get_double_type());
+    }
+
+    #[test]
+    fn test_eval_top_level() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("def add(a b) a + b;")).unwrap();
+        let ast = parse("add(4, 38);");
+        assert_eq!(generator.eval_top_level(&ast), Ok(42.0));
+        // The anonymous function is removed, so its name can be reused.
+        let ast = parse("if 1 < 2 then 3 else 4;");
+        assert_eq!(generator.eval_top_level(&ast), Ok(3.0));
+        let ast = parse("def f(x) x;");
+        assert_eq!(
+            generator.eval_top_level(&ast),
+            Err(LLVMError::NotAnExpression)
+        );
+    }
+
+    #[test]
+    fn test_as_const_double() {
+        let mut generator = IRGenerator::new();
+        let sum = |lhs, rhs| {
+            ExprKind::BinaryOp {
+                op: Operator::Plus,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            }
+            .into()
+        };
+        let value = generator
+            .gen(&sum(
+                ExprKind::Number(2.0).into(),
+                ExprKind::Number(2.5).into(),
+            ))
+            .unwrap();
+        assert_eq!(value.as_const_double(), Some(4.5));
+        let value = generator
+            .gen(&sum(
+                ExprKind::Integer(2).into(),
+                ExprKind::Integer(2).into(),
+            ))
+            .unwrap();
+        assert_eq!(value.as_const_double(), Some(4.0));
+
+        generator.gen(&parse("def f(x) x + 2 * 3;")).unwrap();
+        let f = generator.module.get_function("f").unwrap();
+        assert_eq!(LLVMValue::from(f).as_const_double(), None);
+        let f = generator.module.get_function("f").unwrap();
+        assert_eq!(f.constant_result(), None);
+        generator.gen(&parse("def g() 2 + 2 * 3;")).unwrap();
+        let g = generator.module.get_function("g").unwrap();
+        assert_eq!(g.constant_result(), Some(8.0));
+
+        assert_eq!(generator.eval_top_level(&parse("2 + 2;")), Ok(4.0));
+    }
+
+    #[test]
+    fn test_constant_result_with_side_effects() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn count(_x: f64) -> f64 {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            0.0
+        }
+
+        let mut generator = IRGenerator::new();
+        generator.register_extern("testcount", count as *const ());
+        generator.gen(&parse("extern testcount(x);")).unwrap();
+        assert_eq!(
+            generator.eval_top_level(&parse("var x = testcount(65) in 0;")),
+            Ok(0.0)
+        );
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+        generator
+            .gen(&parse("def h() var x = testcount(1) in 0;"))
+            .unwrap();
+        let h = generator.module.get_function("h").unwrap();
+        assert_eq!(h.constant_result(), None);
+    }
+
+    #[test]
+    fn test_recursive_function() {
+        let mut generator = IRGenerator::new();
+        let fib = generator
+            .gen(&parse(
+                "def fib(n) if n < 2 then n else fib(n-1) + fib(n-2);",
+            ))
+            .unwrap()
+            .to_string();
+        assert_eq!(fib.matches("call double @fib(").count(), 2, "{}", fib);
+        assert!(generator.verify_module().is_ok());
+        assert!(!generator.module_to_string().contains("@fib."));
+        assert_eq!(generator.eval_top_level(&parse("fib(10);")), Ok(55.0));
+    }
+
+    #[test]
+    fn test_definitions_persist_across_inputs() {
+        let mut generator = IRGenerator::new();
+        let mut parser = Parser::new(Vec::new().into_iter());
+        let mut results = Vec::new();
+        for line in &[
+            "def double(x) x * 2;",
+            "extern sin(x);",
+            "double(21) + sin(0);",
+        ] {
+            let tokens = tokenize(line).unwrap();
+            parser.feed(tokens.into_iter());
+            for ast in parser.parse_all().unwrap() {
+                match &ast.kind {
+                    ExprKind::Function { proto, .. } if proto.is_anonymous() => {
+                        results.push(generator.eval_top_level(&ast))
+                    }
+                    _ => {
+                        generator.gen(&ast).unwrap();
+                    }
+                }
+            }
+        }
+        assert_eq!(results, vec![Ok(42.0)]);
+    }
+
+    #[test]
+    fn test_extern() {
+        extern "C" fn triple(x: f64) -> f64 {
+            x * 3.0
+        }
+
+        let mut generator = IRGenerator::new();
+        generator.register_extern("testtriple", triple as *const ());
+        generator.gen(&parse("extern testtriple(x);")).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("testtriple(2);")), Ok(6.0));
+
+        generator.gen(&parse("extern atan2(y x);")).unwrap();
+        generator.gen(&parse("extern sqrt(x);")).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("atan2(0, 1);")), Ok(0.0));
+        assert_eq!(generator.eval_top_level(&parse("sqrt(16);")), Ok(4.0));
+
+        // An unknown symbol is an error rather than an abort in MCJIT, also
+        // when a function that calls it is JIT-compiled with the expression.
+        generator.gen(&parse("extern nosuchfunction(x);")).unwrap();
+        let unresolved = Err(LLVMError::UnresolvedExtern("nosuchfunction".to_string()));
+        assert_eq!(
+            generator.eval_top_level(&parse("nosuchfunction(1);")),
+            unresolved
+        );
+        generator
+            .gen(&parse("def f(x) nosuchfunction(x);"))
+            .unwrap();
+        assert_eq!(generator.eval_top_level(&parse("sqrt(4);")), unresolved);
+        // Declaring it without calling it is fine.
+        generator.gen(&parse("def f(x) x;")).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("sqrt(16);")), Ok(4.0));
+    }
+
+    #[test]
+    fn test_putchard_output() {
+        use std::sync::Mutex;
+
+        static OUTPUT: Mutex<String> = Mutex::new(String::new());
+        extern "C" fn putchard(x: f64) -> f64 {
+            OUTPUT.lock().unwrap().push(x as u8 as char);
+            0.0
+        }
+
+        let mut generator = IRGenerator::new();
+        generator.register_extern("testputchard", putchard as *const ());
+        generator.gen(&parse("extern testputchard(char);")).unwrap();
+        assert_eq!(
+            generator.eval_top_level(&parse("testputchard(65);")),
+            Ok(0.0)
+        );
+        generator
+            .eval_top_level(&parse("for i = 66, i < 68 in testputchard(i);"))
+            .unwrap();
+        // The end condition is checked after the body, as in the tutorial.
+        assert_eq!(*OUTPUT.lock().unwrap(), "ABCD");
+    }
+
+    #[test]
+    fn test_libm_extern() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("extern cos(x);")).unwrap();
+        assert!(generator
+            .module_to_string()
+            .contains("declare double @cos(double"));
+        let value = generator.eval_top_level(&parse("cos(0);")).unwrap();
+        assert!((value - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_if_verifies() {
+        let mut generator = IRGenerator::new();
+        let ast = parse("def f(a b) if a < b then (if b then a else b) else a + b;");
+        generator.gen(&ast).unwrap();
+        assert_eq!(generator.verify_module(), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_module() {
+        let mut generator = IRGenerator::new();
+        let f = generator
+            .gen_proto(&Prototype::new("f".to_string(), Vec::new()))
+            .unwrap();
+        // A block without a terminator is invalid.
+        generator.context.append_basic_block(&f, "entry");
+        match generator.verify_module() {
+            Err(LLVMError::VerificationFailed(message)) => {
+                assert!(message.contains("does not have terminator"), "{}", message)
+            }
+            result => panic!("{:?}", result),
+        }
+        assert!(!f.verify());
+        // A new definition is verified on its own.
+        let g = generator.gen(&parse("def g(x) x + 1;")).unwrap();
+        assert!(FunctionRef::new(g.ptr).verify());
+        assert!(generator.verify_module().is_err());
+    }
+
+    #[test]
+    fn test_for() {
+        let mut generator = IRGenerator::new();
+        let ast = parse("def f(n) for i = 1, i < n, 1.0 in n * i;");
+        generator.gen(&ast).unwrap();
+        assert_eq!(generator.verify_module(), Ok(()));
+        assert_eq!(generator.eval_top_level(&parse("f(3);")), Ok(0.0));
+
+        // The loop variable shadows the parameter only inside the loop.
+        let ast = parse("def g(i) (for i = 1, i < 3 in i) + i;");
+        generator.gen(&ast).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("g(10);")), Ok(10.0));
+    }
+
+    #[test]
+    fn test_nested_scopes() {
+        let mut generator = IRGenerator::new();
+        let ast = parse(
+            "def f(i) var s = 0 in \
+             (for i = 1, i < 2 in (for i = 10, i < 10 in s = s + i) + (s = s + i)) + s + i;",
+        );
+        generator.gen(&ast).unwrap();
+        // The inner loop adds 10 and the outer loop variable 1, then 2.
+        assert_eq!(generator.eval_top_level(&parse("f(100);")), Ok(123.0));
+
+        let ast = parse("def g(x) (for i = 1, i < 2 in i) + i;");
+        assert_eq!(
+            generator.gen(&ast),
+            Err(LLVMError::VariableNotFound("i".to_string()))
+        );
+        let ast = parse("def h(x) (var t = x in t) + t;");
+        assert_eq!(
+            generator.gen(&ast),
+            Err(LLVMError::VariableNotFound("t".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_var_in() {
+        let mut generator = IRGenerator::new_optimized();
+        let f = generator
+            .gen(&parse("def f(x) var y = x+1 in y*2;"))
+            .unwrap();
+        // mem2reg promotes every alloca back into registers.
+        assert!(!f.to_string().contains("alloca"), "{}", f);
+        assert_eq!(generator.eval_top_level(&parse("f(3);")), Ok(8.0));
+
+        let ast = parse("def g(a) (var a = a * 2, b in a + b) + a;");
+        generator.gen(&ast).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("g(5);")), Ok(15.0));
+    }
+
+    #[test]
+    fn test_assign() {
+        let mut generator = IRGenerator::new();
+        let ast = parse("def f(x) var y = 1 in (y = x + 1) * y;");
+        generator.gen(&ast).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("f(2);")), Ok(9.0));
+
+        let ast = parse("def g(x) var y in x = y = x * 2;");
+        generator.gen(&ast).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("g(4);")), Ok(8.0));
+
+        // The end condition is checked after the body runs, so this sums 1
+        // through n.
+        let ast = parse("def sum(n) var acc = 0 in (for i = 1, i < n in acc = acc + i) + acc;");
+        generator.gen(&ast).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("sum(4);")), Ok(10.0));
+
+        assert_eq!(
+            generator.gen(&parse("def h(x) 1 = x;")),
+            Err(LLVMError::InvalidAssignment)
+        );
+        assert_eq!(
+            generator.gen(&parse("def h(x) z = x;")),
+            Err(LLVMError::VariableNotFound("z".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_binary_operator() {
+        let mut generator = IRGenerator::new();
+        let tokens = tokenize(
+            "def binary| 5 (LHS RHS) if LHS then 1 else if RHS then 1 else 0; 0 | 1; 0 | 0;",
+        )
+        .unwrap();
+        let mut parser = Parser::new(tokens.into_iter());
+        generator.gen(&parser.parse().unwrap()).unwrap();
+        assert_eq!(generator.eval_top_level(&parser.parse().unwrap()), Ok(1.0));
+        assert_eq!(generator.eval_top_level(&parser.parse().unwrap()), Ok(0.0));
+
+        // A sequencing operator with the lowest precedence evaluates to its
+        // right operand.
+        let tokens = tokenize("def binary : 1 (x y) y; 1 + 2 : 3 * 4;").unwrap();
+        parser.feed(tokens.into_iter());
+        generator.gen(&parser.parse().unwrap()).unwrap();
+        assert_eq!(generator.eval_top_level(&parser.parse().unwrap()), Ok(12.0));
+    }
+
+    #[test]
+    fn test_unary_operator() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("def unary-(v) 0-v;")).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("-3;")), Ok(-3.0));
+        assert_eq!(generator.eval_top_level(&parse("--3 + -1;")), Ok(2.0));
+        assert_eq!(generator.eval_top_level(&parse("-2 + 5;")), Ok(3.0));
+    }
+
+    #[test]
+    fn test_negation() {
+        let mut generator = IRGenerator::new();
+        assert_eq!(generator.eval_top_level(&parse("-5;")), Ok(-5.0));
+        assert_eq!(generator.eval_top_level(&parse("3 * -2;")), Ok(-6.0));
+        assert_eq!(generator.eval_top_level(&parse("- -4;")), Ok(4.0));
+        generator.gen(&parse("def f(a b) a - -b;")).unwrap();
+        assert_eq!(generator.eval_top_level(&parse("f(1, 2);")), Ok(3.0));
+        assert_eq!(
+            generator.eval_top_level(&parse("!1;")),
+            Err(LLVMError::FunctionNotFound("unary!".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_optimization() {
+        let mut generator = IRGenerator::new_optimized();
+        let f = generator.gen(&parse("def f(x) x * 1.0;")).unwrap();
+        assert!(f.to_string().contains("ret double %x"), "{}", f);
+        let f = generator.gen(&parse("def g(x) (1+2+x);")).unwrap();
+        assert!(
+            f.to_string().contains("fadd double %x, 3.000000e+00"),
+            "{}",
+            f
+        );
+        // GVN merges the two identical additions.
+        let f = generator
+            .gen(&parse("def h(x) (1+2+x)*(x+(1+2));"))
+            .unwrap();
+        assert_eq!(f.to_string().matches("fadd").count(), 1, "{}", f);
+
+        let mut generator = IRGenerator::new();
+        let g = generator.gen(&parse("def g(x) x * 1.0;")).unwrap();
+        assert!(g.to_string().contains("fmul double %x"), "{}", g);
+        let h = generator
+            .gen(&parse("def h(x) (1+2+x)*(x+(1+2));"))
+            .unwrap();
+        assert_eq!(h.to_string().matches("fadd").count(), 2, "{}", h);
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        let mut generator = IRGenerator::new();
+        let cases = [
+            ("1 < 2;", 1.0),
+            ("2 > 2;", 0.0),
+            ("2 <= 2;", 1.0),
+            ("1 >= 2;", 0.0),
+            ("2==2;", 1.0),
+            ("1 != 1;", 0.0),
+        ];
+        for (input, expected) in cases.iter() {
+            assert_eq!(
+                generator.eval_top_level(&parse(input)),
+                Ok(*expected),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_module_to_string() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("extern sin(x);")).unwrap();
+        generator.gen(&parse("def f(x) sin(x);")).unwrap();
+        let ir = generator.module_to_string();
+        assert!(ir.contains("declare double @sin(double)"), "{}", ir);
+        assert!(ir.contains("define double @f(double %x)"), "{}", ir);
+
+        let f = generator.module.get_function("f").unwrap();
+        assert!(f.to_string().starts_with("define double @f"), "{}", f);
+    }
+
+    #[test]
+    fn test_condition_stays_boolean() {
+        let mut generator = IRGenerator::new();
+        let ast = parse("def fib(x) if x < 3 then 1 else fib(x-1)+fib(x-2);");
+        let fib = generator.gen(&ast).unwrap().to_string();
+        assert!(fib.contains("fcmp olt"), "{}", fib);
+        assert!(!fib.contains("uitofp"), "{}", fib);
+        assert_eq!(generator.eval_top_level(&parse("fib(10);")), Ok(55.0));
+
+        // A comparison used as a value is still converted to a double.
+        let f = generator.gen(&parse("def f(x) (x < 3) + 1;")).unwrap();
+        assert!(f.to_string().contains("uitofp"), "{}", f);
+
+        // Any other condition is compared against zero.
+        let g = generator
+            .gen(&parse("def g(x) if x then 1 else 2;"))
+            .unwrap()
+            .to_string();
+        assert!(g.contains("fcmp one double %x2, 0.000000e+00"), "{}", g);
+        assert!(!g.contains("uitofp"), "{}", g);
+        assert_eq!(generator.eval_top_level(&parse("g(0.5);")), Ok(1.0));
+        assert_eq!(generator.eval_top_level(&parse("g(0);")), Ok(2.0));
+        assert_eq!(
+            generator.eval_top_level(&parse("if 3 - 3 then 1 else 2;")),
+            Ok(2.0)
+        );
+    }
+
+    #[test]
+    fn test_fcmp_predicate() {
+        let mut generator = IRGenerator::new_optimized();
+        let lt = generator.gen(&parse("def lt(a b) a < b;")).unwrap();
+        let gt = generator.gen(&parse("def gt(a b) a > b;")).unwrap();
+        let (lt, gt) = (lt.to_string(), gt.to_string());
+        assert!(lt.contains("fcmp olt double %a, %b"), "{}", lt);
+        assert!(gt.contains("fcmp ogt double %a, %b"), "{}", gt);
+    }
+
+    #[test]
+    fn test_write_object_file() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("def f(x) x + 1;")).unwrap();
+
+        let path = std::env::temp_dir().join(format!("kaleidoscope-{}.o", std::process::id()));
+        generator
+            .write_object_file(&path, &TargetOptions::default())
+            .unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        const ELF: &[u8] = b"\x7fELF";
+        const MACH_O: &[u8] = &[0xcf, 0xfa, 0xed, 0xfe];
+        assert!(
+            bytes.starts_with(ELF) || bytes.starts_with(MACH_O),
+            "{:?}",
+            &bytes[..bytes.len().min(4)]
+        );
+    }
+
+    #[test]
+    fn test_write_object_file_for_target() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("def f(x) x + 1;")).unwrap();
+        let ir = generator.module_to_string();
+        let path =
+            std::env::temp_dir().join(format!("kaleidoscope-{}-aarch64.o", std::process::id()));
+        let target = TargetOptions {
+            triple: Some("aarch64-unknown-linux-gnu".to_string()),
+            ..TargetOptions::default()
+        };
+        generator.write_object_file(&path, &target).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(bytes.starts_with(b"\x7fELF"));
+        // e_machine is EM_AARCH64.
+        assert_eq!(&bytes[18..20], &[0xb7, 0x00]);
+        // The target is only set on the emitted copy.
+        assert_eq!(generator.module_to_string(), ir);
+
+        let target = TargetOptions {
+            triple: Some("bogus-none-nowhere".to_string()),
+            ..TargetOptions::default()
+        };
+        assert!(matches!(
+            generator.write_object_file(&path, &target),
+            Err(LLVMError::UnknownTarget(_))
+        ));
+    }
+
+    #[test]
+    fn test_write_bitcode() {
+        let mut generator = IRGenerator::new();
+        generator.gen(&parse("def f(x) x + 1;")).unwrap();
+
+        let path = std::env::temp_dir().join(format!("kaleidoscope-{}.bc", std::process::id()));
+        generator.write_bitcode(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(bytes.starts_with(b"BC\xC0\xDE"), "{:?}", &bytes[..4]);
+
+        let path = std::env::temp_dir().join("no-such-directory").join("f.bc");
+        assert_eq!(
+            generator.write_bitcode(&path),
+            Err(LLVMError::WriteBitcode(path.to_string_lossy().into_owned()))
+        );
     }
 }
@@ -1,12 +1,23 @@
-use std::num::ParseFloatError;
+use std::fmt;
+use std::num::{ParseFloatError, ParseIntError};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
-    EOF,
     Def,
     Extern,
+    Binary,
+    Unary,
+    If,
+    Then,
+    Else,
+    For,
+    In,
+    Var,
     Identifier(String), // IdentifierStr
-    Number(f64),        // NumVal
+    // NumVal, with the literal as written in the source.
+    Number { value: f64, raw: String },
+    Integer { value: i64, raw: String },
+    StringLiteral(String),
     OpenParenthesis,
     CloseParenthesis,
     SemiColon,
@@ -14,29 +25,152 @@ pub enum Token {
     Operator(Operator),
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Def => write!(f, "'def'"),
+            Self::Extern => write!(f, "'extern'"),
+            Self::Binary => write!(f, "'binary'"),
+            Self::Unary => write!(f, "'unary'"),
+            Self::If => write!(f, "'if'"),
+            Self::Then => write!(f, "'then'"),
+            Self::Else => write!(f, "'else'"),
+            Self::For => write!(f, "'for'"),
+            Self::In => write!(f, "'in'"),
+            Self::Var => write!(f, "'var'"),
+            Self::Identifier(name) => write!(f, "identifier '{}'", name),
+            Self::Number { raw, .. } | Self::Integer { raw, .. } => write!(f, "number {}", raw),
+            Self::StringLiteral(value) => write!(f, "string {:?}", value),
+            Self::OpenParenthesis => write!(f, "'('"),
+            Self::CloseParenthesis => write!(f, "')'"),
+            Self::SemiColon => write!(f, "';'"),
+            Self::Comma => write!(f, "','"),
+            Self::Operator(op) => write!(f, "'{}'", op),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Operator {
     LessThan,
+    GreaterThan,
+    LessEqual,
+    GreaterEqual,
+    Equal,
+    NotEqual,
     Plus,
     Minus,
     Times,
+    Divide,
+    Power,
+    Assign,
+    Custom(char),
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::LessThan => write!(f, "<"),
+            Self::GreaterThan => write!(f, ">"),
+            Self::LessEqual => write!(f, "<="),
+            Self::GreaterEqual => write!(f, ">="),
+            Self::Equal => write!(f, "=="),
+            Self::NotEqual => write!(f, "!="),
+            Self::Plus => write!(f, "+"),
+            Self::Minus => write!(f, "-"),
+            Self::Times => write!(f, "*"),
+            Self::Divide => write!(f, "/"),
+            Self::Power => write!(f, "^"),
+            Self::Assign => write!(f, "="),
+            Self::Custom(c) => write!(f, "{}", c),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl<T> Spanned<T> {
+    pub fn span(&self) -> Span {
+        Span {
+            line: self.line,
+            col: self.col,
+        }
+    }
+}
+
+/// A position in the source, counted from 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum LexerError {
     InvalidNumber(ParseFloatError),
+    InvalidInteger(ParseIntError),
+    InvalidDigitSeparator,
+    UnterminatedBlockComment,
+    UnterminatedString,
+    InvalidEscape(char),
     UnknownChar(char),
 }
 
+impl fmt::Display for LexerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidNumber(err) => write!(f, "invalid number: {}", err),
+            Self::InvalidInteger(err) => write!(f, "invalid integer: {}", err),
+            Self::InvalidDigitSeparator => write!(f, "'_' must be between two digits"),
+            Self::UnterminatedBlockComment => write!(f, "unterminated block comment"),
+            Self::UnterminatedString => write!(f, "unterminated string literal"),
+            Self::InvalidEscape(c) => write!(f, "invalid escape sequence '\\{}'", c),
+            Self::UnknownChar(c) => write!(f, "unknown character '{}'", c),
+        }
+    }
+}
+
+impl std::error::Error for LexerError {}
+
+impl<T: fmt::Display> fmt::Display for Spanned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}:{}", self.value, self.line, self.col)
+    }
+}
+
+impl<T: fmt::Debug + fmt::Display> std::error::Error for Spanned<T> {}
+
 impl From<ParseFloatError> for LexerError {
     fn from(err: ParseFloatError) -> Self {
         Self::InvalidNumber(err)
     }
 }
 
+impl From<ParseIntError> for LexerError {
+    fn from(err: ParseIntError) -> Self {
+        Self::InvalidInteger(err)
+    }
+}
+
 pub struct Lexer<I> {
     iter: I,
     last_char: Option<char>,
+    line: usize,
+    col: usize,
+    token_start: (usize, usize),
 }
 
 impl<I> Lexer<I>
@@ -46,11 +180,29 @@ where
     pub fn new(iter: I) -> Self {
         let mut iter = iter;
         let last_char = iter.next();
-        Self { iter, last_char }
+        Self {
+            iter,
+            last_char,
+            line: 1,
+            col: 1,
+            token_start: (1, 1),
+        }
     }
 
     fn consume_char(&mut self) {
-        self.last_char = self.iter.next();
+        let next = self.iter.next();
+        match self.last_char {
+            // A lone `\r` ends a line too; in `\r\n` only the `\n` counts.
+            Some('\n') => self.new_line(),
+            Some('\r') if next != Some('\n') => self.new_line(),
+            _ => self.col += 1,
+        }
+        self.last_char = next;
+    }
+
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.col = 1;
     }
 
     fn get_char(&mut self) -> Option<char> {
@@ -59,52 +211,205 @@ where
         c
     }
 
-    fn get_token(&mut self) -> Result<Token, LexerError> {
-        if let Some(c) = self.last_char {
-            if c.is_ascii_whitespace() {
-                self.skip_chars(char::is_ascii_whitespace);
+    fn spanned<T>(&self, value: T) -> Spanned<T> {
+        let (line, col) = self.token_start;
+        Spanned { value, line, col }
+    }
+
+    /// Reads the next token, skipping whitespace and comments. Returns
+    /// `None` at the end of input.
+    fn read_token(&mut self) -> Result<Option<Token>, LexerError> {
+        loop {
+            self.skip_chars(char::is_ascii_whitespace);
+            self.token_start = (self.line, self.col);
+            let c = match self.get_char() {
+                Some(c) => c,
+                None => return Ok(None),
+            };
+            match c {
+                '#' => self.skip_comment(),
+                '/' if self.consume_if('/') => self.skip_comment(),
+                '/' if self.consume_if('*') => self.skip_block_comment()?,
+                _ => return self.lex_token(c).map(Some),
             }
         }
+    }
 
-        if let Some(c) = self.get_char() {
-            if c.is_ascii_alphabetic() {
-                let ident = self.get_chars(c, char::is_ascii_alphanumeric);
+    fn lex_token(&mut self, c: char) -> Result<Token, LexerError> {
+        if c.is_ascii_alphabetic() || c == '_' {
+            let ident = self.get_chars(c.to_string(), |c| c.is_ascii_alphanumeric() || c == &'_');
 
-                return Ok(match ident.as_str() {
-                    "def" => Token::Def,
-                    "extern" => Token::Extern,
-                    _ => Token::Identifier(ident),
-                });
-            }
+            return Ok(match ident.as_str() {
+                "def" => Token::Def,
+                "extern" => Token::Extern,
+                "binary" => Token::Binary,
+                "unary" => Token::Unary,
+                "if" => Token::If,
+                "then" => Token::Then,
+                "else" => Token::Else,
+                "for" => Token::For,
+                "in" => Token::In,
+                "var" => Token::Var,
+                _ => Token::Identifier(ident),
+            });
+        }
 
-            if c.is_ascii_digit() || c == '.' {
-                let num = self.get_chars(c, |c| c.is_ascii_digit() || c == &'.');
+        if c.is_ascii_digit() || c == '.' {
+            return self.read_number(c);
+        }
+
+        match c {
+            '"' => self.read_string(),
+            '(' => Ok(Token::OpenParenthesis),
+            ')' => Ok(Token::CloseParenthesis),
+            ';' => Ok(Token::SemiColon),
+            ',' => Ok(Token::Comma),
+            '<' if self.consume_if('=') => Ok(Token::Operator(Operator::LessEqual)),
+            '<' => Ok(Token::Operator(Operator::LessThan)),
+            '>' if self.consume_if('=') => Ok(Token::Operator(Operator::GreaterEqual)),
+            '>' => Ok(Token::Operator(Operator::GreaterThan)),
+            '=' if self.consume_if('=') => Ok(Token::Operator(Operator::Equal)),
+            '=' => Ok(Token::Operator(Operator::Assign)),
+            '!' if self.consume_if('=') => Ok(Token::Operator(Operator::NotEqual)),
+            '+' => Ok(Token::Operator(Operator::Plus)),
+            '-' => Ok(Token::Operator(Operator::Minus)),
+            '*' => Ok(Token::Operator(Operator::Times)),
+            '/' => Ok(Token::Operator(Operator::Divide)),
+            '^' => Ok(Token::Operator(Operator::Power)),
+            _ if c.is_ascii_punctuation() => Ok(Token::Operator(Operator::Custom(c))),
+            _ => Err(LexerError::UnknownChar(c)),
+        }
+    }
 
-                return Ok(Token::Number(num.parse()?));
+    fn read_number(&mut self, first: char) -> Result<Token, LexerError> {
+        let hex_prefix = self.last_char.filter(|c| matches!(c, 'x' | 'X'));
+        if let Some(x) = hex_prefix.filter(|_| first == '0') {
+            self.consume_char();
+            let digits = self.get_digits(String::new(), char::is_ascii_hexdigit)?;
+            let raw = format!("0{}{}", x, digits);
+            let digits = digits.replace('_', "");
+            return match i64::from_str_radix(&digits, 16) {
+                Ok(value) => Ok(Token::Integer { value, raw }),
+                // Too large for an `i64`, but still a valid double. Only an
+                // empty literal fails otherwise.
+                Err(_) if !digits.is_empty() => Ok(Token::Number {
+                    value: hex_to_f64(&digits),
+                    raw,
+                }),
+                Err(err) => Err(err.into()),
+            };
+        }
+
+        let mut num = self.get_digits(first.to_string(), char::is_ascii_digit)?;
+        let mut is_integer = first != '.';
+        // Only one '.' belongs to a number, so `1..2` lexes as `1.` and `.2`.
+        if first != '.' && self.consume_if('.') {
+            is_integer = false;
+            num.push('.');
+            num = self.get_digits(num, char::is_ascii_digit)?;
+        }
+        if let Some(e) = self.last_char.filter(|c| c == &'e' || c == &'E') {
+            is_integer = false;
+            num.push(e);
+            self.consume_char();
+            if let Some(sign) = self.last_char.filter(|c| c == &'+' || c == &'-') {
+                num.push(sign);
+                self.consume_char();
+            }
+            num = self.get_digits(num, char::is_ascii_digit)?;
+        }
+        let value = num.replace('_', "");
+        if is_integer {
+            // Too large for an `i64`, but still a valid double.
+            if let Ok(value) = value.parse() {
+                return Ok(Token::Integer { value, raw: num });
             }
+        }
+        Ok(Token::Number {
+            value: value.parse()?,
+            raw: num,
+        })
+    }
 
-            match c {
-                '#' => {
-                    self.skip_chars(|c| c != &'\n' && c != &'\r');
-
-                    if self.last_char.is_some() {
-                        self.get_token()
-                    } else {
-                        Ok(Token::EOF)
-                    }
+    /// Reads the rest of a string literal after its opening `"`.
+    fn read_string(&mut self) -> Result<Token, LexerError> {
+        let mut value = String::new();
+        loop {
+            match self.get_char() {
+                Some('"') => return Ok(Token::StringLiteral(value)),
+                Some('\\') => match self.get_char() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('\\') => value.push('\\'),
+                    Some('"') => value.push('"'),
+                    Some(c) => return Err(LexerError::InvalidEscape(c)),
+                    None => return Err(LexerError::UnterminatedString),
+                },
+                Some(c) => value.push(c),
+                None => return Err(LexerError::UnterminatedString),
+            }
+        }
+    }
+
+    /// Like `get_chars`, but also skips `_` separators between two digits.
+    fn get_digits<P: Fn(&char) -> bool>(
+        &mut self,
+        initial: String,
+        is_digit: P,
+    ) -> Result<String, LexerError> {
+        let mut digits = initial;
+        let mut after_digit = digits.ends_with(|c: char| is_digit(&c));
+        let mut after_separator = false;
+        while let Some(c) = self.last_char {
+            if is_digit(&c) {
+                digits.push(c);
+                after_digit = true;
+                after_separator = false;
+            } else if c == '_' {
+                if !after_digit {
+                    return Err(LexerError::InvalidDigitSeparator);
                 }
-                '(' => Ok(Token::OpenParenthesis),
-                ')' => Ok(Token::CloseParenthesis),
-                ';' => Ok(Token::SemiColon),
-                ',' => Ok(Token::Comma),
-                '<' => Ok(Token::Operator(Operator::LessThan)),
-                '+' => Ok(Token::Operator(Operator::Plus)),
-                '-' => Ok(Token::Operator(Operator::Minus)),
-                '*' => Ok(Token::Operator(Operator::Times)),
-                _ => Err(LexerError::UnknownChar(c)),
+                digits.push(c);
+                after_digit = false;
+                after_separator = true;
+            } else {
+                break;
+            }
+            self.consume_char();
+        }
+        if after_separator {
+            return Err(LexerError::InvalidDigitSeparator);
+        }
+        Ok(digits)
+    }
+
+    /// Skips the rest of the line, leaving the line ending to the whitespace
+    /// skipper.
+    fn skip_comment(&mut self) {
+        self.skip_chars(|c| c != &'\n' && c != &'\r');
+    }
+
+    /// Skips a `/* ... */` comment. Block comments nest, so commenting out
+    /// code that already contains one works as expected.
+    fn skip_block_comment(&mut self) -> Result<(), LexerError> {
+        let mut depth = 1;
+        while depth > 0 {
+            match self.get_char() {
+                Some('*') if self.consume_if('/') => depth -= 1,
+                Some('/') if self.consume_if('*') => depth += 1,
+                Some(_) => {}
+                None => return Err(LexerError::UnterminatedBlockComment),
             }
+        }
+        Ok(())
+    }
+
+    fn consume_if(&mut self, expected: char) -> bool {
+        if self.last_char == Some(expected) {
+            self.consume_char();
+            true
         } else {
-            Ok(Token::EOF)
+            false
         }
     }
 
@@ -117,8 +422,8 @@ where
         }
     }
 
-    fn get_chars<P: Fn(&char) -> bool>(&mut self, initial: char, predicate: P) -> String {
-        let mut chars = initial.to_string();
+    fn get_chars<P: Fn(&char) -> bool>(&mut self, initial: String, predicate: P) -> String {
+        let mut chars = initial;
         while let Some(c) = self.last_char {
             if !predicate(&c) {
                 break;
@@ -134,34 +439,390 @@ impl<I> Iterator for Lexer<I>
 where
     I: Iterator<Item = char>,
 {
-    type Item = Result<Token, LexerError>;
+    type Item = Result<Spanned<Token>, Spanned<LexerError>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.get_token() {
-            Ok(token) => {
-                if token == Token::EOF {
-                    None
-                } else {
-                    Some(Ok(token))
-                }
-            }
-            Err(err) => Some(Err(err)),
+        match self.read_token() {
+            Ok(token) => token.map(|token| Ok(self.spanned(token))),
+            Err(err) => Some(Err(self.spanned(err))),
         }
     }
 }
 
+/// Lexes all of `src`, stopping at the first error.
+pub fn tokenize(src: &str) -> Result<Vec<Spanned<Token>>, Spanned<LexerError>> {
+    Lexer::new(src.chars()).collect()
+}
+
+/// Converts hexadecimal digits to the nearest double.
+fn hex_to_f64(digits: &str) -> f64 {
+    match u128::from_str_radix(digits, 16) {
+        Ok(value) => value as f64,
+        Err(_) => digits.chars().fold(0.0, |acc, c| {
+            acc * 16.0 + f64::from(c.to_digit(16).unwrap())
+        }),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn integer(value: i64) -> Token {
+        Token::Integer {
+            value,
+            raw: value.to_string(),
+        }
+    }
+
+    fn tokens(input: &str) -> Vec<Result<Token, LexerError>> {
+        Lexer::new(input.chars())
+            .map(|token| token.map(|token| token.value).map_err(|err| err.value))
+            .collect()
+    }
+
     #[test]
     fn test_lexer() {
         let input = "3.141592 def fib x";
         let mut lexer = Lexer::new(input.chars());
-        assert_eq!(lexer.get_token(), Ok(Token::Number(3.141592)));
-        assert_eq!(lexer.get_token(), Ok(Token::Def));
-        assert_eq!(lexer.get_token(), Ok(Token::Identifier("fib".to_string())));
-        assert_eq!(lexer.get_token(), Ok(Token::Identifier("x".to_string())));
-        assert_eq!(lexer.get_token(), Ok(Token::EOF));
+        let mut get_token = || {
+            lexer
+                .next()
+                .map(|token| token.map(|token| token.value).map_err(|err| err.value))
+        };
+        assert_eq!(
+            get_token(),
+            Some(Ok(Token::Number {
+                value: 3.141592,
+                raw: "3.141592".to_string()
+            }))
+        );
+        assert_eq!(get_token(), Some(Ok(Token::Def)));
+        assert_eq!(get_token(), Some(Ok(Token::Identifier("fib".to_string()))));
+        assert_eq!(get_token(), Some(Ok(Token::Identifier("x".to_string()))));
+        assert_eq!(get_token(), None);
+    }
+
+    #[test]
+    fn test_position() {
+        let input = "def f(x)\n  # comment\n  x + 1;";
+        let positions: Vec<_> = Lexer::new(input.chars())
+            .map(|token| {
+                let token = token.unwrap();
+                (token.line, token.col)
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                (1, 1),
+                (1, 5),
+                (1, 6),
+                (1, 7),
+                (1, 8),
+                (3, 3),
+                (3, 5),
+                (3, 7),
+                (3, 8)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let positions = |input: &str| -> Vec<_> {
+            Lexer::new(input.chars())
+                .map(|token| {
+                    let token = token.unwrap();
+                    (token.value, token.line, token.col)
+                })
+                .collect()
+        };
+        let expected = vec![
+            (Token::Identifier("x".to_string()), 2, 1),
+            (Token::Operator(Operator::Plus), 4, 2),
+            (integer(1), 4, 4),
+        ];
+        assert_eq!(positions("# a\r\nx\r\n # b\r\n + 1 # c"), expected);
+        assert_eq!(positions("# a\rx\r # b\r + 1 # c"), expected);
+        assert_eq!(positions("// a\r\nx\r\n/* b */\r\n + 1 // c"), expected);
+    }
+
+    #[test]
+    fn test_error_position() {
+        let errors: Vec<_> = Lexer::new("x +\n  1e+ é".chars())
+            .filter_map(|token| token.err())
+            .map(|err| (err.line, err.col))
+            .collect();
+        assert_eq!(errors, vec![(2, 3), (2, 7)]);
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        let input = "< <= > >= == != <<=";
+        assert_eq!(
+            tokens(input),
+            vec![
+                Ok(Token::Operator(Operator::LessThan)),
+                Ok(Token::Operator(Operator::LessEqual)),
+                Ok(Token::Operator(Operator::GreaterThan)),
+                Ok(Token::Operator(Operator::GreaterEqual)),
+                Ok(Token::Operator(Operator::Equal)),
+                Ok(Token::Operator(Operator::NotEqual)),
+                Ok(Token::Operator(Operator::LessThan)),
+                Ok(Token::Operator(Operator::LessEqual)),
+            ]
+        );
+
+        let mut lexer = Lexer::new("= x".chars());
+        assert_eq!(
+            lexer
+                .next()
+                .and_then(|token| token.ok())
+                .map(|token| token.value),
+            Some(Token::Operator(Operator::Assign))
+        );
+    }
+
+    #[test]
+    fn test_if_then_else() {
+        let input = "if x then y else z";
+        assert_eq!(
+            tokens(input),
+            vec![
+                Ok(Token::If),
+                Ok(Token::Identifier("x".to_string())),
+                Ok(Token::Then),
+                Ok(Token::Identifier("y".to_string())),
+                Ok(Token::Else),
+                Ok(Token::Identifier("z".to_string())),
+            ]
+        );
+
+        let input = "ifx thenable elsewhere";
+        assert_eq!(
+            tokens(input),
+            vec![
+                Ok(Token::Identifier("ifx".to_string())),
+                Ok(Token::Identifier("thenable".to_string())),
+                Ok(Token::Identifier("elsewhere".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_identifiers_with_underscores() {
+        let identifier = |name: &str| Ok(Token::Identifier(name.to_string()));
+        assert_eq!(
+            tokens("_private a_b_c def_ def"),
+            vec![
+                identifier("_private"),
+                identifier("a_b_c"),
+                identifier("def_"),
+                Ok(Token::Def),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let expected = vec![
+            Ok(integer(1)),
+            Ok(Token::Operator(Operator::Plus)),
+            Ok(integer(2)),
+        ];
+        assert_eq!(tokens("1 + 2 // trailing comment\n"), expected);
+        assert_eq!(tokens("1 + 2 // comment at EOF"), expected);
+        assert_eq!(tokens("# comment\n1 + 2 # another"), expected);
+        assert_eq!(tokens("1 /* block */ + /**/ 2"), expected);
+        assert_eq!(tokens("1 + /* a /* b */ c */ 2"), expected);
+        assert_eq!(tokens("/* a\n * b\n */ 1 + 2"), expected);
+        assert_eq!(
+            tokens("1 /* a /* b */"),
+            vec![Ok(integer(1)), Err(LexerError::UnterminatedBlockComment)]
+        );
+        assert_eq!(
+            tokens("/*/"),
+            vec![Err(LexerError::UnterminatedBlockComment)]
+        );
+        assert_eq!(tokens("/* /* */ */ /**/"), vec![]);
+        assert_eq!(
+            tokens("/* a */ */"),
+            vec![
+                Ok(Token::Operator(Operator::Times)),
+                Ok(Token::Operator(Operator::Divide)),
+            ]
+        );
+        assert_eq!(
+            tokens("1 / 2"),
+            vec![
+                Ok(integer(1)),
+                Ok(Token::Operator(Operator::Divide)),
+                Ok(integer(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_end_of_input() {
+        let x = vec![Ok(Token::Identifier("x".to_string()))];
+        assert_eq!(tokens(""), vec![]);
+        assert_eq!(tokens("# only a comment"), vec![]);
+        assert_eq!(tokens("x # comment"), x);
+        assert_eq!(tokens("x // comment"), x);
+        assert_eq!(tokens("x /* comment */"), x);
+
+        // The end of input is sticky rather than a token of its own.
+        let mut lexer = Lexer::new("x // comment".chars());
+        assert!(lexer.next().is_some());
+        assert_eq!(lexer.next(), None);
+        assert_eq!(lexer.next(), None);
+    }
+
+    #[test]
+    fn test_string_literals() {
+        let string = |value: &str| Ok(Token::StringLiteral(value.to_string()));
+        assert_eq!(
+            tokens(r#"log("x =\n", x)"#),
+            vec![
+                Ok(Token::Identifier("log".to_string())),
+                Ok(Token::OpenParenthesis),
+                string("x =\n"),
+                Ok(Token::Comma),
+                Ok(Token::Identifier("x".to_string())),
+                Ok(Token::CloseParenthesis),
+            ]
+        );
+        assert_eq!(tokens(r#""""#), vec![string("")]);
+        assert_eq!(
+            tokens(r#""a\tb \\ \"c\" # d""#),
+            vec![string("a\tb \\ \"c\" # d")]
+        );
+        assert_eq!(tokens(r#""abc"#), vec![Err(LexerError::UnterminatedString)]);
+        assert_eq!(
+            tokens(r#""abc\"#),
+            vec![Err(LexerError::UnterminatedString)]
+        );
+        assert_eq!(tokens(r#""\q""#)[0], Err(LexerError::InvalidEscape('q')));
+        assert_eq!(
+            LexerError::InvalidEscape('q').to_string(),
+            r"invalid escape sequence '\q'"
+        );
+        let err = tokenize("1 + \"abc").unwrap_err();
+        assert_eq!(err.to_string(), "unterminated string literal at 1:5");
+    }
+
+    #[test]
+    fn test_number_literals() {
+        let numbers = |input: &str| -> Vec<_> {
+            tokens(input)
+                .into_iter()
+                .map(|token| match token {
+                    Ok(Token::Number { value, .. }) => value,
+                    Ok(Token::Integer { value, .. }) => value as f64,
+                    token => panic!("{:?}", token),
+                })
+                .collect()
+        };
+        assert_eq!(numbers("6.022e23 1e-3 2E+2"), vec![6.022e23, 1e-3, 2e2]);
+        assert_eq!(numbers("0xFF 0X1f"), vec![255.0, 31.0]);
+        assert_eq!(numbers("3. .5 1..2"), vec![3.0, 0.5, 1.0, 0.2]);
+        assert_eq!(
+            tokens("3.;"),
+            vec![
+                Ok(Token::Number {
+                    value: 3.0,
+                    raw: "3.".to_string()
+                }),
+                Ok(Token::SemiColon)
+            ]
+        );
+        assert_eq!(numbers("1e3 1.5E-2"), vec![1e3, 1.5e-2]);
+        assert!(matches!(
+            tokens("1e")[..],
+            [Err(LexerError::InvalidNumber(_))]
+        ));
+        assert_eq!(
+            numbers("1_000 1.23_456 0xFF_FF"),
+            vec![1000.0, 1.23456, 65535.0]
+        );
+        let raw = |input: &str| match tokens(input).remove(0) {
+            Ok(Token::Number { raw, .. }) => raw,
+            token => panic!("{:?}", token),
+        };
+        assert_eq!(raw("0.1"), "0.1");
+        assert_eq!(raw("1.23_456"), "1.23_456");
+        assert_eq!(raw("6.022E+23"), "6.022E+23");
+        assert_eq!(raw(".50"), ".50");
+        for input in &["1__2", "1_", "1_.5", "1._5", "1e_5"] {
+            assert!(
+                tokens(input).contains(&Err(LexerError::InvalidDigitSeparator)),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(tokens("_5"), vec![Ok(Token::Identifier("_5".to_string()))]);
+        assert!(matches!(
+            tokens("0x")[..],
+            [Err(LexerError::InvalidInteger(_))]
+        ));
+    }
+
+    #[test]
+    fn test_integer_literals() {
+        assert_eq!(
+            tokens("42 0x2A 1_000 42. 4e1 .5"),
+            vec![
+                Ok(integer(42)),
+                Ok(Token::Integer {
+                    value: 42,
+                    raw: "0x2A".to_string()
+                }),
+                Ok(Token::Integer {
+                    value: 1000,
+                    raw: "1_000".to_string()
+                }),
+                Ok(Token::Number {
+                    value: 42.0,
+                    raw: "42.".to_string()
+                }),
+                Ok(Token::Number {
+                    value: 40.0,
+                    raw: "4e1".to_string()
+                }),
+                Ok(Token::Number {
+                    value: 0.5,
+                    raw: ".5".to_string()
+                }),
+            ]
+        );
+        assert_eq!(
+            tokens("9223372036854775807 9223372036854775808"),
+            vec![
+                Ok(integer(i64::MAX)),
+                Ok(Token::Number {
+                    value: 9223372036854775808.0,
+                    raw: "9223372036854775808".to_string()
+                }),
+            ]
+        );
+        // Hexadecimal literals fall back to a double the same way.
+        assert_eq!(
+            tokens("0x7FFF_FFFF_FFFF_FFFF 0x8000000000000000 0x1_0000_0000_0000_0000_0000_0000_0000_0000"),
+            vec![
+                Ok(Token::Integer {
+                    value: i64::MAX,
+                    raw: "0x7FFF_FFFF_FFFF_FFFF".to_string()
+                }),
+                Ok(Token::Number {
+                    value: 9223372036854775808.0,
+                    raw: "0x8000000000000000".to_string()
+                }),
+                Ok(Token::Number {
+                    value: 2f64.powi(128),
+                    raw: "0x1_0000_0000_0000_0000_0000_0000_0000_0000".to_string()
+                }),
+            ]
+        );
     }
 }
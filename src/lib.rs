@@ -0,0 +1,193 @@
+pub mod ir;
+pub mod lexer;
+pub mod parser;
+pub mod visit;
+
+use crate::ir::{IRGenerator, LLVMError, TargetOptions};
+use crate::lexer::{tokenize, LexerError, Span, Spanned};
+use crate::parser::{Parser, ParserError};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq)]
+pub enum CompileError {
+    Lexer(Spanned<LexerError>),
+    Parser(ParserError),
+    /// A code generation error, located at the node that caused it if that
+    /// node was parsed.
+    Codegen(LLVMError, Option<Span>),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Lexer(err) => write!(f, "{}", err),
+            Self::Parser(err) => write!(f, "{}", err),
+            Self::Codegen(err, Some(span)) => write!(f, "{} at {}", err, span),
+            Self::Codegen(err, None) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+impl From<Spanned<LexerError>> for CompileError {
+    fn from(err: Spanned<LexerError>) -> Self {
+        Self::Lexer(err)
+    }
+}
+
+impl From<ParserError> for CompileError {
+    fn from(err: ParserError) -> Self {
+        Self::Parser(err)
+    }
+}
+
+impl From<LLVMError> for CompileError {
+    fn from(err: LLVMError) -> Self {
+        Self::Codegen(err, None)
+    }
+}
+
+/// Compiles every statement in `src` into one module and returns its IR.
+pub fn compile_str(src: &str) -> Result<String, CompileError> {
+    let mut generator = IRGenerator::new();
+    compile_into(&mut generator, src)?;
+    Ok(generator.module_to_string())
+}
+
+/// Generates every statement in `src` into the module of `generator`.
+/// Functions may be called before their definition.
+pub fn compile_into(generator: &mut IRGenerator, src: &str) -> Result<(), CompileError> {
+    let tokens = tokenize(src)?;
+    let asts = Parser::new(tokens.into_iter()).parse_all()?;
+    generator
+        .gen_all(&asts)
+        .map_err(|err| CompileError::Codegen(err, generator.error_span()))?;
+    Ok(())
+}
+
+/// The output formats `emit` can write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmitKind {
+    LlvmIr,
+    Assembly,
+    Object,
+    Bitcode,
+}
+
+impl EmitKind {
+    /// Guesses the format from the extension of `path`, defaulting to an object file.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ll") => Self::LlvmIr,
+            Some("s") => Self::Assembly,
+            Some("bc") => Self::Bitcode,
+            _ => Self::Object,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::LlvmIr => "ll",
+            Self::Assembly => "s",
+            Self::Object => "o",
+            Self::Bitcode => "bc",
+        }
+    }
+}
+
+impl FromStr for EmitKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "llvm-ir" => Ok(Self::LlvmIr),
+            "asm" => Ok(Self::Assembly),
+            "obj" => Ok(Self::Object),
+            "bitcode" => Ok(Self::Bitcode),
+            _ => Err(format!(
+                "unknown emit kind '{}': expected llvm-ir, asm, obj or bitcode",
+                s
+            )),
+        }
+    }
+}
+
+/// Writes the module of `generator` to `path` as `kind`. Object and assembly
+/// files are generated for `target`.
+pub fn emit(
+    generator: &IRGenerator,
+    kind: EmitKind,
+    path: &Path,
+    target: &TargetOptions,
+) -> Result<(), LLVMError> {
+    match kind {
+        EmitKind::LlvmIr => generator.write_llvm_ir(path),
+        EmitKind::Assembly => generator.write_assembly_file(path, target),
+        EmitKind::Object => generator.write_object_file(path, target),
+        EmitKind::Bitcode => generator.write_bitcode(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_str() {
+        let ir = compile_str("extern sin(x); def f(x) sin(x) + 1;").unwrap();
+        assert!(ir.contains("declare double @sin(double)"), "{}", ir);
+        assert!(ir.contains("define double @f(double %x)"), "{}", ir);
+
+        assert!(matches!(
+            compile_str("def f(x) x +;"),
+            Err(CompileError::Parser(_))
+        ));
+        assert!(matches!(
+            compile_str("def f(x) y;"),
+            Err(CompileError::Codegen(LLVMError::VariableNotFound(_), _))
+        ));
+        assert_eq!(
+            compile_str("def f(x)\n  x + g(x);")
+                .unwrap_err()
+                .to_string(),
+            "function 'g' not found at 2:7"
+        );
+        assert!(matches!(
+            compile_str("def f(x) x; def f(y z) y;"),
+            Err(CompileError::Codegen(
+                LLVMError::PrototypeMismatch { .. },
+                Some(Span { line: 1, col: 13 })
+            ))
+        ));
+        assert_eq!(
+            compile_str("1 + é").unwrap_err().to_string(),
+            "unknown character 'é' at 1:5"
+        );
+    }
+
+    #[test]
+    fn test_emit() {
+        let mut generator = IRGenerator::new();
+        compile_into(&mut generator, "def f(x) x;").unwrap();
+        for kind in &["llvm-ir", "asm", "obj", "bitcode"] {
+            let kind: EmitKind = kind.parse().unwrap();
+            let path = std::env::temp_dir().join(format!(
+                "kaleidoscope-emit-{}.{}",
+                std::process::id(),
+                kind.extension()
+            ));
+            emit(&generator, kind, &path, &TargetOptions::default()).unwrap();
+            assert_eq!(EmitKind::from_path(&path), kind);
+            let bytes = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert!(!bytes.is_empty(), "{:?}", kind);
+        }
+        assert_eq!(
+            "exe".parse::<EmitKind>(),
+            Err("unknown emit kind 'exe': expected llvm-ir, asm, obj or bitcode".to_string())
+        );
+    }
+}
@@ -1,56 +1,399 @@
-mod ir;
-mod lexer;
-mod parser;
+use kaleidoscope::ir::{IRGenerator, TargetOptions};
+use kaleidoscope::lexer::{tokenize, LexerError, Spanned, Token};
+use kaleidoscope::parser::{ExprAST, ExprKind, Parser, ParserError};
+use kaleidoscope::{compile_into, emit, CompileError, EmitKind};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::process;
+use std::sync::OnceLock;
 
-use crate::ir::*;
-use crate::lexer::Lexer;
-use crate::parser::Parser;
-use std::io::{self, Write};
+/// Prints `err` to stderr, in color unless `NO_COLOR` is set or stderr is not
+/// a terminal.
+fn print_error(err: impl fmt::Display) {
+    static COLOR: OnceLock<bool> = OnceLock::new();
+    let color = *COLOR.get_or_init(|| {
+        env::var_os("NO_COLOR")
+            .filter(|value| !value.is_empty())
+            .is_none()
+            && io::stderr().is_terminal()
+    });
+    if color {
+        eprintln!("\x1b[1;31merror\x1b[m: {}", err);
+    } else {
+        eprintln!("error: {}", err);
+    }
+}
+
+extern "C" fn putchard(x: f64) -> f64 {
+    print!("{}", x as u8 as char);
+    0.0
+}
+
+extern "C" fn printd(x: f64) -> f64 {
+    println!("{}", x);
+    0.0
+}
 
 fn main() -> io::Result<()> {
-    let mut generator = IRGenerator::new();
+    let args: Vec<String> = env::args().collect();
+    let output = args
+        .iter()
+        .position(|arg| arg == "-o")
+        .and_then(|i| args.get(i + 1))
+        .map(Path::new);
+    // When several of `--emit=<kind>`, `--emit-llvm` and `--emit-obj` are
+    // given, the last one wins. Without any, the kind follows the extension
+    // of `-o`, and IR is the default.
+    let mut emit_kind = None;
+    for arg in &args {
+        let kind = match arg.as_str() {
+            "--emit-llvm" => EmitKind::LlvmIr,
+            "--emit-obj" => EmitKind::Object,
+            arg => match arg.strip_prefix("--emit=").map(str::parse) {
+                Some(Ok(kind)) => kind,
+                Some(Err(err)) => {
+                    print_error(err);
+                    process::exit(2);
+                }
+                None => continue,
+            },
+        };
+        emit_kind = Some(kind);
+    }
+    // Object and assembly files are generated for the host unless overridden.
+    let flag = |name: &str| {
+        args.iter()
+            .rev()
+            .find_map(|arg| arg.strip_prefix(name))
+            .map(str::to_string)
+    };
+    let target = TargetOptions {
+        triple: flag("--target="),
+        cpu: flag("--cpu="),
+        features: flag("--features="),
+    };
+    // Arguments other than flags and the value of `-o`.
+    let mut positional = Vec::new();
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        if arg == "-o" {
+            rest.next();
+        } else if !arg.starts_with('-') {
+            positional.push(arg.as_str());
+        }
+    }
+
+    // Stops after lexing and prints the tokens instead.
+    let tokens_only = args.iter().any(|arg| arg == "--tokens");
+    // Prints the tree of each statement; in the REPL, before evaluating it.
+    let dump_ast = args.iter().any(|arg| arg == "--ast");
+
+    let mut generator = if args.iter().any(|arg| arg == "-O0") {
+        IRGenerator::new()
+    } else {
+        IRGenerator::new_optimized()
+    };
+    generator.register_extern("putchard", putchard as *const ());
+    generator.register_extern("printd", printd as *const ());
+
+    // `kaleidoscope <input>` is short for `kaleidoscope build <input>`.
+    let input = match positional[..] {
+        ["build", input, ..] => input,
+        ["build"] => {
+            eprintln!(
+                "usage: {} [build] <input> [-o <output>] [--emit=llvm-ir|asm|obj|bitcode] \
+                 [--emit-llvm] [--emit-obj] [--target=<triple>] [--cpu=<cpu>] \
+                 [--features=<features>] [--tokens] [--ast] [-O0] [-g]",
+                args[0]
+            );
+            process::exit(2);
+        }
+        [input, ..] => input,
+        [] => {
+            let output =
+                output.map(|path| (emit_kind.unwrap_or_else(|| EmitKind::from_path(path)), path));
+            return repl(&mut generator, output, &target, tokens_only, dump_ast);
+        }
+    };
+    if tokens_only {
+        match read_tokens(input) {
+            Ok(tokens) => print_tokens(&tokens),
+            Err(err) => {
+                print_error(err);
+                process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if dump_ast {
+        match read_asts(input) {
+            Ok(asts) => asts.iter().for_each(|ast| print!("{}", ast.pretty(0))),
+            Err(err) => {
+                print_error(err);
+                process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if args.iter().any(|arg| arg == "-g") {
+        generator.enable_debug_info(Path::new(input));
+    }
+    let kind = emit_kind
+        .or_else(|| output.map(EmitKind::from_path))
+        .unwrap_or(EmitKind::LlvmIr);
+    let output = match output {
+        Some(output) => Some(output.to_path_buf()),
+        // IR is printed rather than written next to the input.
+        None if kind == EmitKind::LlvmIr => None,
+        None => Some(Path::new(input).with_extension(kind.extension())),
+    };
+    if let Err(err) = build(&mut generator, input, kind, output.as_deref(), &target) {
+        print_error(err);
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Compiles every statement in the file at `input` and writes the result to
+/// `output`, or prints the IR to stdout if there is none.
+fn build(
+    generator: &mut IRGenerator,
+    input: &str,
+    kind: EmitKind,
+    output: Option<&Path>,
+    target: &TargetOptions,
+) -> Result<(), Box<dyn Error>> {
+    let source = fs::read_to_string(input)?;
+    compile_into(generator, &source)?;
+    if matches!(kind, EmitKind::Object | EmitKind::Assembly) {
+        generator.add_entry_point()?;
+    }
+    match output {
+        Some(output) => emit(generator, kind, output, target)?,
+        None => print!("{}", generator.module_to_string()),
+    }
+    Ok(())
+}
+
+fn read_tokens(input: &str) -> Result<Vec<Spanned<Token>>, Box<dyn Error>> {
+    let source = fs::read_to_string(input)?;
+    Ok(tokenize(&source)?)
+}
+
+fn read_asts(input: &str) -> Result<Vec<ExprAST>, Box<dyn Error>> {
+    let tokens = read_tokens(input)?;
+    Ok(Parser::new(tokens.into_iter()).parse_all()?)
+}
+
+/// Generates the definitions in the file at `input` into the live module and
+/// returns how many there were. They are parsed by the REPL's `parser`, so
+/// operators they define keep their precedence afterwards, and a definition
+/// may call one that comes later in the file. The top-level expressions of
+/// the file are evaluated after all of them.
+fn load(
+    generator: &mut IRGenerator,
+    parser: &mut Parser<std::vec::IntoIter<Spanned<Token>>>,
+    input: &str,
+) -> Result<usize, Box<dyn Error>> {
+    parser.feed(read_tokens(input)?.into_iter());
+    let (expressions, definitions): (Vec<_>, Vec<_>) = parser.parse_all()?.into_iter().partition(
+        |ast| matches!(&ast.kind, ExprKind::Function { proto, .. } if proto.is_anonymous()),
+    );
+    generator
+        .gen_all(&definitions)
+        .map_err(|err| CompileError::Codegen(err, generator.error_span()))?;
+    for ast in expressions {
+        match generator.eval_top_level(&ast) {
+            Ok(value) => println!("=> {}", value),
+            Err(err) => print_error(CompileError::Codegen(err, generator.error_span())),
+        }
+    }
+    Ok(definitions.len())
+}
+
+fn print_tokens(tokens: &[Spanned<Token>]) {
+    for token in tokens {
+        println!("{}", token);
+    }
+}
+
+/// Lists the REPL's meta-commands. The `:` prefix keeps them apart from
+/// Kaleidoscope input.
+fn print_help() {
+    println!(":load <file>  generate every statement in <file>");
+    println!(":verify       verify the module");
+    println!(":ast          toggle printing the tree of each statement");
+    #[cfg(feature = "serde")]
+    println!(":json         toggle printing each statement as JSON");
+    println!(":quit         print the module and exit (also quit)");
+    println!(":help         show this list");
+}
+
+fn repl(
+    generator: &mut IRGenerator,
+    output: Option<(EmitKind, &Path)>,
+    target: &TargetOptions,
+    tokens_only: bool,
+    mut dump_ast: bool,
+) -> io::Result<()> {
+    let mut parser = Parser::new(Vec::new().into_iter());
+    #[cfg(feature = "serde")]
+    let mut dump_json = false;
+    // Holds the lines of a statement that is still incomplete, and why.
+    let mut buffer = String::new();
+    let mut incomplete: Option<Box<dyn Error>> = None;
+    println!("Type :help for a list of commands.");
     loop {
-        print!("parser> ");
+        print!(
+            "{}",
+            if buffer.is_empty() {
+                "parser> "
+            } else {
+                "   ...> "
+            }
+        );
         io::stdout().flush()?;
 
-        let mut buffer = String::new();
-        io::stdin().read_line(&mut buffer)?;
+        let mut line = String::new();
+        let eof = io::stdin().read_line(&mut line)? == 0;
 
-        if buffer.trim().is_empty() {
-            continue;
-        }
-        if buffer.trim() == "quit" {
+        if eof || (buffer.is_empty() && matches!(line.trim(), ":quit" | "quit")) {
             generator.dump_module();
+            if let Some((kind, output)) = output {
+                if let Err(err) = emit(generator, kind, output, target) {
+                    print_error(err);
+                }
+            }
             break;
         }
+        if buffer.is_empty() && line.trim() == ":verify" {
+            match generator.verify_module() {
+                Ok(()) => println!("module is valid"),
+                Err(err) => print_error(err),
+            }
+            continue;
+        }
+        if buffer.is_empty() && line.trim() == ":ast" {
+            dump_ast = !dump_ast;
+            println!("AST dump {}", if dump_ast { "on" } else { "off" });
+            continue;
+        }
+        if buffer.is_empty() && line.trim() == ":help" {
+            print_help();
+            continue;
+        }
+        #[cfg(feature = "serde")]
+        {
+            if buffer.is_empty() && line.trim() == ":json" {
+                dump_json = !dump_json;
+                println!("JSON dump {}", if dump_json { "on" } else { "off" });
+                continue;
+            }
+        }
+        match line.trim().strip_prefix(":load ") {
+            Some(input) if buffer.is_empty() => {
+                match load(generator, &mut parser, input.trim()) {
+                    Ok(count) => println!("loaded {} definitions", count),
+                    Err(err) => print_error(err),
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if buffer.is_empty() && line.trim().starts_with(':') {
+            print_error(format_args!(
+                "unknown command '{}', type :help",
+                line.trim()
+            ));
+            continue;
+        }
+        if line.trim().is_empty() {
+            // A blank line abandons an incomplete statement.
+            if let Some(err) = incomplete.take() {
+                print_error(err);
+                buffer.clear();
+            }
+            continue;
+        }
+        buffer.push_str(&line);
+        incomplete = None;
 
-        let lexer = Lexer::new(buffer.chars());
-        let tokens = lexer.collect::<Result<Vec<_>, _>>();
-        let tokens = match tokens {
+        let tokens = match tokenize(&buffer) {
             Ok(tokens) => tokens,
+            // A comment or string may continue on the following lines.
+            Err(
+                err @ Spanned {
+                    value: LexerError::UnterminatedBlockComment | LexerError::UnterminatedString,
+                    ..
+                },
+            ) => {
+                incomplete = Some(err.into());
+                continue;
+            }
             Err(err) => {
-                eprintln!("\x1b[1;31merror\x1b[m: {:?}", err);
+                print_error(err);
+                buffer.clear();
                 continue;
             }
         };
+        if tokens_only {
+            print_tokens(&tokens);
+            buffer.clear();
+            continue;
+        }
 
-        let mut parser = Parser::new(tokens.into_iter());
-        let ast = match parser.parse() {
-            Ok(ast) => ast,
+        parser.feed(tokens.into_iter());
+        let asts = match parser.parse_all() {
+            Ok(asts) => asts,
+            // Wait for the rest of the statement on the following lines.
+            Err(err @ ParserError::UnexpectedEof { .. }) => {
+                incomplete = Some(err.into());
+                continue;
+            }
             Err(err) => {
-                eprintln!("\x1b[1;31merror\x1b[m: {}", err);
+                print_error(err);
+                buffer.clear();
                 continue;
             }
         };
-        // println!("{:?}", ast);
+        buffer.clear();
 
-        match generator.gen(&ast) {
-            Ok(ir) => {
-                ir.dump();
-                println!();
+        for ast in asts {
+            if dump_ast {
+                print!("{}", ast.pretty(0));
             }
-            Err(err) => {
-                eprintln!("\x1b[1;31merror\x1b[m: {:?}", err);
+            #[cfg(feature = "serde")]
+            {
+                if dump_json {
+                    match serde_json::to_string(&ast) {
+                        Ok(json) => println!("{}", json),
+                        Err(err) => print_error(err),
+                    }
+                }
+            }
+
+            if let ExprKind::Function { proto, .. } = &ast.kind {
+                if proto.is_anonymous() {
+                    match generator.eval_top_level(&ast) {
+                        Ok(value) => println!("=> {}", value),
+                        Err(err) => print_error(CompileError::Codegen(err, generator.error_span())),
+                    }
+                    continue;
+                }
+            }
+
+            match generator.gen(&ast) {
+                Ok(ir) => {
+                    ir.dump();
+                    println!();
+                }
+                Err(err) => {
+                    print_error(CompileError::Codegen(err, generator.error_span()));
+                }
             }
         }
     }
@@ -1,210 +1,2041 @@
 use crate::lexer::*;
-use std::iter::Peekable;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 
 #[derive(Debug, PartialEq)]
-pub enum ExprAST {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExprKind {
     Number(f64),
+    Integer(i64),
+    // Only valid as an argument of a call, which receives a pointer to the
+    // NUL-terminated string.
+    StringLiteral(String),
     Variable(String),
     BinaryOp {
         op: Operator,
-        lhs: Box<Self>,
-        rhs: Box<Self>,
+        lhs: Box<ExprAST>,
+        rhs: Box<ExprAST>,
+    },
+    UnaryOp {
+        op: Operator,
+        operand: Box<ExprAST>,
     },
     Call {
         callee: String,
-        args: Vec<Self>,
+        args: Vec<ExprAST>,
+    },
+    If {
+        cond: Box<ExprAST>,
+        then_branch: Box<ExprAST>,
+        else_branch: Box<ExprAST>,
+    },
+    For {
+        var_name: String,
+        start: Box<ExprAST>,
+        end: Box<ExprAST>,
+        step: Option<Box<ExprAST>>,
+        body: Box<ExprAST>,
+    },
+    VarIn {
+        bindings: Vec<(String, Option<ExprAST>)>,
+        body: Box<ExprAST>,
     },
     Prototype(Prototype),
     Function {
         proto: Prototype,
-        body: Box<Self>,
+        body: Box<ExprAST>,
     },
 }
 
+/// A node of the syntax tree, with where it starts in the source if it was
+/// parsed. Spans don't take part in comparisons, so a parsed tree equals one
+/// built by hand.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExprAST {
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub kind: ExprKind,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub span: Option<Span>,
+}
+
+impl ExprAST {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self {
+            kind,
+            span: Some(span),
+        }
+    }
+}
+
+impl From<ExprKind> for ExprAST {
+    fn from(kind: ExprKind) -> Self {
+        Self { kind, span: None }
+    }
+}
+
+impl PartialEq for ExprAST {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+/// Renders the expression as source, parenthesizing every binary operation and
+/// every `if`, `for` or `var` used as an operand.
+impl fmt::Display for ExprAST {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Number(value) => write!(f, "{:?}", value),
+            ExprKind::Integer(value) => write!(f, "{}", value),
+            ExprKind::StringLiteral(value) => {
+                write!(f, "\"")?;
+                for c in value.chars() {
+                    match c {
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '"' => write!(f, "\\\"")?,
+                        _ => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            ExprKind::Variable(name) => write!(f, "{}", name),
+            ExprKind::BinaryOp { op, lhs, rhs } => {
+                write!(f, "(")?;
+                fmt_operand(lhs, f)?;
+                write!(f, " {} ", op)?;
+                fmt_operand(rhs, f)?;
+                write!(f, ")")
+            }
+            ExprKind::UnaryOp { op, operand } => {
+                write!(f, "{}", op)?;
+                fmt_operand(operand, f)
+            }
+            ExprKind::Call { callee, args } => {
+                write!(f, "{}(", callee)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => write!(f, "if {} then {} else {}", cond, then_branch, else_branch),
+            ExprKind::For {
+                var_name,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                write!(f, "for {} = {}, {}", var_name, start, end)?;
+                if let Some(step) = step {
+                    write!(f, ", {}", step)?;
+                }
+                write!(f, " in {}", body)
+            }
+            ExprKind::VarIn { bindings, body } => {
+                write!(f, "var ")?;
+                for (i, (name, init)) in bindings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", name)?;
+                    if let Some(init) = init {
+                        write!(f, " = {}", init)?;
+                    }
+                }
+                write!(f, " in {}", body)
+            }
+            ExprKind::Prototype(proto) => write!(f, "extern {}", proto),
+            ExprKind::Function { proto, body } if proto.is_anonymous() => write!(f, "{}", body),
+            ExprKind::Function { proto, body } => write!(f, "def {} {}", proto, body),
+        }
+    }
+}
+
+/// Writes `expr` as an operand. `if`, `for` and `var` extend as far to the
+/// right as possible, so they are parenthesized.
+fn fmt_operand(expr: &ExprAST, f: &mut fmt::Formatter) -> fmt::Result {
+    match &expr.kind {
+        ExprKind::If { .. } | ExprKind::For { .. } | ExprKind::VarIn { .. } => {
+            write!(f, "({})", expr)
+        }
+        _ => write!(f, "{}", expr),
+    }
+}
+
+/// The signature of a function. Its fields are public, so tools can read a
+/// parsed definition:
+///
+/// ```
+/// use kaleidoscope::lexer::tokenize;
+/// use kaleidoscope::parser::{ExprKind, Parser};
+///
+/// let tokens = tokenize("def f(a b) a").unwrap();
+/// let ast = Parser::new(tokens.into_iter()).parse().unwrap();
+/// match ast.kind {
+///     ExprKind::Function { proto, .. } => {
+///         assert_eq!(proto.name, "f");
+///         assert_eq!(proto.args, ["a", "b"]);
+///     }
+///     kind => panic!("not a function: {:?}", kind),
+/// }
+/// ```
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Prototype {
     pub name: String,
     pub args: Vec<String>,
+    pub is_operator: bool,
+    pub precedence: Option<u8>,
+}
+
+const ANONYMOUS_FUNCTION_NAME: &str = "__anon_expr";
+/// The precedence of a binary operator that is defined without one, and of an
+/// operator that has no precedence in the parser's table.
+const DEFAULT_BINARY_PRECEDENCE: u8 = 30;
+
+impl Prototype {
+    pub fn new(name: String, args: Vec<String>) -> Self {
+        Self {
+            name,
+            args,
+            is_operator: false,
+            precedence: None,
+        }
+    }
+
+    pub fn anonymous() -> Self {
+        Self::new(ANONYMOUS_FUNCTION_NAME.to_string(), Vec::new())
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.name == ANONYMOUS_FUNCTION_NAME
+    }
+}
+
+impl ExprAST {
+    /// Renders the expression as source, with parentheses only where the
+    /// default precedences and associativities require them.
+    pub fn to_source(&self) -> String {
+        let unparser = Unparser {
+            precedence: default_precedence(),
+            associativity: default_associativity(),
+        };
+        let mut out = String::new();
+        unparser.write(&mut out, self);
+        out
+    }
+
+    /// Renders the tree with one node per line, each child indented two
+    /// spaces more than its parent, starting at `indent` spaces.
+    pub fn pretty(&self, indent: usize) -> String {
+        let mut out = String::new();
+        self.write_tree(&mut out, indent);
+        out
+    }
+
+    fn write_tree(&self, out: &mut String, indent: usize) {
+        let line = |out: &mut String, indent: usize, label: &dyn fmt::Display| {
+            out.push_str(&format!("{:indent$}{}\n", "", label, indent = indent));
+        };
+        let child = indent + 2;
+        match &self.kind {
+            ExprKind::Number(value) => line(out, indent, &format_args!("Number({:?})", value)),
+            ExprKind::Integer(value) => line(out, indent, &format_args!("Integer({})", value)),
+            ExprKind::StringLiteral(value) => {
+                line(out, indent, &format_args!("String({:?})", value))
+            }
+            ExprKind::Variable(name) => line(out, indent, &format_args!("Variable({})", name)),
+            ExprKind::BinaryOp { op, lhs, rhs } => {
+                line(out, indent, &format_args!("BinaryOp({})", op));
+                lhs.write_tree(out, child);
+                rhs.write_tree(out, child);
+            }
+            ExprKind::UnaryOp { op, operand } => {
+                line(out, indent, &format_args!("UnaryOp({})", op));
+                operand.write_tree(out, child);
+            }
+            ExprKind::Call { callee, args } => {
+                line(out, indent, &format_args!("Call({})", callee));
+                for arg in args {
+                    arg.write_tree(out, child);
+                }
+            }
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                line(out, indent, &"If");
+                cond.write_tree(out, child);
+                then_branch.write_tree(out, child);
+                else_branch.write_tree(out, child);
+            }
+            ExprKind::For {
+                var_name,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                line(out, indent, &format_args!("For({})", var_name));
+                start.write_tree(out, child);
+                end.write_tree(out, child);
+                if let Some(step) = step {
+                    step.write_tree(out, child);
+                }
+                body.write_tree(out, child);
+            }
+            ExprKind::VarIn { bindings, body } => {
+                line(out, indent, &"VarIn");
+                for (name, init) in bindings {
+                    line(out, child, &format_args!("Binding({})", name));
+                    if let Some(init) = init {
+                        init.write_tree(out, child + 2);
+                    }
+                }
+                body.write_tree(out, child);
+            }
+            ExprKind::Prototype(proto) => line(out, indent, &format_args!("Extern {}", proto)),
+            ExprKind::Function { proto, body } => {
+                if proto.is_anonymous() {
+                    line(out, indent, &"Expression");
+                } else {
+                    line(out, indent, &format_args!("Function {}", proto));
+                }
+                body.write_tree(out, child);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Prototype {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(precedence) = self.precedence {
+            write!(f, " {}", precedence)?;
+        }
+        write!(f, "({})", self.args.join(" "))
+    }
 }
 
-type ParserError = &'static str;
+struct Unparser {
+    precedence: HashMap<Operator, u8>,
+    associativity: HashMap<Operator, Associativity>,
+}
+
+impl Unparser {
+    fn write(&self, out: &mut String, expr: &ExprAST) {
+        match &expr.kind {
+            ExprKind::BinaryOp { op, lhs, rhs } => {
+                let precedence = self.precedence.get(op).copied();
+                let right = self.associativity.get(op) == Some(&Associativity::Right);
+                self.write_operand(out, lhs, self.needs_parens(lhs, precedence, right));
+                out.push_str(&format!(" {} ", op));
+                self.write_operand(out, rhs, self.needs_parens(rhs, precedence, !right));
+            }
+            ExprKind::UnaryOp { op, operand } => {
+                out.push_str(&op.to_string());
+                // Keep two operators from lexing as one.
+                if let ExprKind::UnaryOp { .. } = operand.kind {
+                    out.push(' ');
+                }
+                self.write_operand(out, operand, self.needs_parens(operand, None, true));
+            }
+            ExprKind::Call { callee, args } => {
+                out.push_str(callee);
+                out.push('(');
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    self.write(out, arg);
+                }
+                out.push(')');
+            }
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                out.push_str("if ");
+                self.write(out, cond);
+                out.push_str(" then ");
+                self.write(out, then_branch);
+                out.push_str(" else ");
+                self.write(out, else_branch);
+            }
+            ExprKind::For {
+                var_name,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                out.push_str(&format!("for {} = ", var_name));
+                self.write(out, start);
+                out.push_str(", ");
+                self.write(out, end);
+                if let Some(step) = step {
+                    out.push_str(", ");
+                    self.write(out, step);
+                }
+                out.push_str(" in ");
+                self.write(out, body);
+            }
+            ExprKind::VarIn { bindings, body } => {
+                out.push_str("var ");
+                for (i, (name, init)) in bindings.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    out.push_str(name);
+                    if let Some(init) = init {
+                        out.push_str(" = ");
+                        self.write(out, init);
+                    }
+                }
+                out.push_str(" in ");
+                self.write(out, body);
+            }
+            ExprKind::Function { proto, body } => {
+                if !proto.is_anonymous() {
+                    out.push_str(&format!("def {} ", proto));
+                }
+                self.write(out, body);
+            }
+            _ => out.push_str(&expr.to_string()),
+        }
+    }
+
+    fn write_operand(&self, out: &mut String, operand: &ExprAST, parens: bool) {
+        if parens {
+            out.push('(');
+            self.write(out, operand);
+            out.push(')');
+        } else {
+            self.write(out, operand);
+        }
+    }
+
+    /// Whether `operand` needs parentheses as an operand of an operator of
+    /// `precedence`, or of a unary operator if it is `None`. `on_tie` tells
+    /// whether it does when both precedences are the same.
+    fn needs_parens(&self, operand: &ExprAST, precedence: Option<u8>, on_tie: bool) -> bool {
+        match &operand.kind {
+            ExprKind::BinaryOp { op, .. } => match (self.precedence.get(op), precedence) {
+                (Some(&inner), Some(outer)) => inner < outer || (inner == outer && on_tie),
+                // Operators without a default precedence are always
+                // parenthesized.
+                _ => true,
+            },
+            // These extend as far to the right as possible.
+            ExprKind::If { .. } | ExprKind::For { .. } | ExprKind::VarIn { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+enum PrototypeKind {
+    Function,
+    Unary,
+    Binary(Operator, u8),
+}
+
+/// Which side a chain of operators of the same precedence groups on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Associativity {
+    Left,
+    Right,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParserError {
+    UnexpectedToken {
+        found: Spanned<Token>,
+        expected: &'static str,
+    },
+    UnexpectedEof {
+        expected: &'static str,
+    },
+    Invalid {
+        message: String,
+        line: usize,
+        col: usize,
+    },
+}
+
+impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnexpectedToken { found, expected } => write!(
+                f,
+                "expected {}, found {} at {}:{}",
+                expected, found.value, found.line, found.col
+            ),
+            Self::UnexpectedEof { expected } => {
+                write!(f, "expected {}, found end of input", expected)
+            }
+            Self::Invalid { message, line, col } => write!(f, "{} at {}:{}", message, line, col),
+        }
+    }
+}
+
+impl std::error::Error for ParserError {}
+
 type Result<T> = std::result::Result<T, ParserError>;
 
+fn default_precedence() -> HashMap<Operator, u8> {
+    let mut precedence = HashMap::new();
+    precedence.insert(Operator::Assign, 2);
+    precedence.insert(Operator::LessThan, 10);
+    precedence.insert(Operator::GreaterThan, 10);
+    precedence.insert(Operator::LessEqual, 10);
+    precedence.insert(Operator::GreaterEqual, 10);
+    precedence.insert(Operator::Equal, 10);
+    precedence.insert(Operator::NotEqual, 10);
+    precedence.insert(Operator::Plus, 20);
+    precedence.insert(Operator::Minus, 20);
+    precedence.insert(Operator::Times, 40);
+    precedence.insert(Operator::Divide, 40);
+    precedence.insert(Operator::Power, 60);
+    precedence
+}
+
+fn default_associativity() -> HashMap<Operator, Associativity> {
+    let mut associativity = HashMap::new();
+    associativity.insert(Operator::Assign, Associativity::Right);
+    associativity.insert(Operator::Power, Associativity::Right);
+    associativity
+}
+
+/// Like `std::iter::Peekable`, but can look two items ahead.
+pub struct Peekable2<I: Iterator> {
+    iter: I,
+    // Items taken from `iter` but not yet returned, at most two.
+    buffer: VecDeque<I::Item>,
+}
+
+impl<I: Iterator> Peekable2<I> {
+    pub fn new(iter: I) -> Self {
+        Self {
+            iter,
+            buffer: VecDeque::with_capacity(2),
+        }
+    }
+
+    pub fn peek(&mut self) -> Option<&I::Item> {
+        self.fill(1);
+        self.buffer.front()
+    }
+
+    /// Returns the item after the one `peek` returns.
+    pub fn peek2(&mut self) -> Option<&I::Item> {
+        self.fill(2);
+        self.buffer.get(1)
+    }
+
+    fn fill(&mut self, len: usize) {
+        while self.buffer.len() < len {
+            match self.iter.next() {
+                Some(item) => self.buffer.push_back(item),
+                None => break,
+            }
+        }
+    }
+}
+
+impl<I: Iterator> Iterator for Peekable2<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        self.buffer.pop_front().or_else(|| self.iter.next())
+    }
+}
+
 pub struct Parser<I>
 where
-    I: Iterator<Item = Token>,
+    I: Iterator<Item = Spanned<Token>>,
 {
-    iter: Peekable<I>,
+    iter: Peekable2<I>,
+    binop_precedence: HashMap<Operator, u8>,
+    binop_associativity: HashMap<Operator, Associativity>,
+    last: Option<Spanned<Token>>,
 }
 
 impl<I> Parser<I>
 where
-    I: Iterator<Item = Token>,
+    I: Iterator<Item = Spanned<Token>>,
 {
     pub fn new(iter: I) -> Self {
         Self {
-            iter: iter.peekable(),
+            iter: Peekable2::new(iter),
+            binop_precedence: default_precedence(),
+            binop_associativity: default_associativity(),
+            last: None,
         }
     }
 
+    /// Creates a parser whose binary operator precedences are the defaults
+    /// overridden by `precedence`.
+    pub fn with_precedence(iter: I, precedence: HashMap<Operator, u8>) -> Self {
+        let mut parser = Self::new(iter);
+        parser.binop_precedence.extend(precedence);
+        parser
+    }
+
+    /// Sets the precedence of the binary operator `op`, making it usable as a
+    /// binary operator if it was not already.
+    pub fn set_precedence(&mut self, op: Operator, precedence: u8) {
+        self.binop_precedence.insert(op, precedence);
+    }
+
+    /// Sets the associativity of the binary operator `op`. Operators are
+    /// left-associative unless set otherwise.
+    pub fn set_associativity(&mut self, op: Operator, associativity: Associativity) {
+        self.binop_associativity.insert(op, associativity);
+    }
+
+    pub fn associativity(&self, op: Operator) -> Associativity {
+        self.binop_associativity
+            .get(&op)
+            .copied()
+            .unwrap_or(Associativity::Left)
+    }
+
+    /// Replaces the remaining tokens, keeping the operators defined so far.
+    pub fn feed(&mut self, iter: I) {
+        self.iter = Peekable2::new(iter);
+    }
+
+    /// Parses one statement and the semicolon terminating it, if any.
     pub fn parse(&mut self) -> Result<ExprAST> {
-        let ast = match self.iter.peek() {
+        let ast = match self.peek() {
             Some(Token::Def) => {
-                self.iter.next();
+                self.next();
                 self.parse_defeinition()?
             }
             Some(Token::Extern) => {
-                self.iter.next();
+                self.next();
                 self.parse_extern()?
             }
             Some(_) => {
-                let proto = Prototype {
-                    name: "__anon_expr".to_string(),
-                    args: Vec::new(),
-                };
+                let proto = Prototype::anonymous();
                 let body = Box::new(self.parse_expression()?);
-                ExprAST::Function { proto, body }
+                let span = body.span;
+                ExprAST {
+                    kind: ExprKind::Function { proto, body },
+                    span,
+                }
             }
             None => {
-                return Err("Unimplemented");
+                return Err(ParserError::UnexpectedEof {
+                    expected: "expression",
+                });
             }
         };
 
-        match self.iter.peek() {
-            Some(Token::SemiColon) => {
-                self.iter.next();
-            }
-            Some(_) => {
-                let mut remainds = Vec::new();
-                for token in &mut self.iter {
-                    remainds.push(token);
+        match self.next() {
+            Some(Token::SemiColon) | None => Ok(ast),
+            Some(_) => Err(self.unexpected("';' after statement")),
+        }
+    }
+
+    /// Parses every statement up to the end of the tokens, skipping empty ones.
+    pub fn parse_all(&mut self) -> Result<Vec<ExprAST>> {
+        let mut asts = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::SemiColon) => {
+                    self.next();
                 }
-                eprintln!("\x1b[1;33mwarning\x1b[m: Invalid syntax: {:?}", remainds);
-            }
-            None => {
-                eprintln!("\x1b[1;33mwarning\x1b[m: Expected semicolon");
+                Some(_) => asts.push(self.parse()?),
+                None => return Ok(asts),
             }
         }
-        Ok(ast)
     }
 
     fn parse_defeinition(&mut self) -> Result<ExprAST> {
+        let span = self.last_span();
         let proto = self.parse_prototype()?;
         let body = self.parse_expression()?;
-        Ok(ExprAST::Function {
+        let kind = ExprKind::Function {
             proto,
             body: Box::new(body),
-        })
+        };
+        Ok(ExprAST::new(kind, span))
     }
 
     fn parse_extern(&mut self) -> Result<ExprAST> {
-        Ok(ExprAST::Prototype(self.parse_prototype()?))
+        let span = self.last_span();
+        let kind = ExprKind::Prototype(self.parse_prototype()?);
+        Ok(ExprAST::new(kind, span))
     }
 
     fn parse_prototype(&mut self) -> Result<Prototype> {
-        if let Some(Token::Identifier(name)) = self.iter.next() {
-            if self.iter.next() != Some(Token::OpenParenthesis) {
-                return Err("Expected '(' in prototype");
+        let (name, kind) = match self.next() {
+            Some(Token::Identifier(name)) => (name, PrototypeKind::Function),
+            Some(Token::Unary) => match self.next() {
+                Some(Token::Operator(op)) => (format!("unary{}", op), PrototypeKind::Unary),
+                _ => return Err(self.unexpected("unary operator")),
+            },
+            Some(Token::Binary) => {
+                let op = match self.next() {
+                    Some(Token::Operator(op)) => op,
+                    _ => return Err(self.unexpected("binary operator")),
+                };
+                // Built-in operators are generated directly and keep their
+                // precedence.
+                if !matches!(op, Operator::Custom(_)) {
+                    return Err(self.error(format!("cannot redefine built-in operator '{}'", op)));
+                }
+                let precedence = match self.peek() {
+                    Some(Token::Integer { value, .. }) => {
+                        let value = *value;
+                        self.next();
+                        if !(1..=100).contains(&value) {
+                            return Err(self.error("invalid precedence: must be 1..100"));
+                        }
+                        value as u8
+                    }
+                    _ => DEFAULT_BINARY_PRECEDENCE,
+                };
+                (
+                    format!("binary{}", op),
+                    PrototypeKind::Binary(op, precedence),
+                )
             }
-            let mut args = Vec::new();
-            while let Some(Token::Identifier(arg)) = self.iter.peek() {
-                args.push(arg.clone());
-                self.iter.next();
+            _ => return Err(self.unexpected("function name in prototype")),
+        };
+
+        if self.next() != Some(Token::OpenParenthesis) {
+            return Err(self.unexpected("'(' in prototype"));
+        }
+        let mut args = Vec::new();
+        while let Some(Token::Identifier(arg)) = self.peek() {
+            let arg = arg.clone();
+            self.next();
+            if args.contains(&arg) {
+                return Err(self.error(format!("duplicate parameter '{}'", arg)));
             }
-            if self.iter.next() != Some(Token::CloseParenthesis) {
-                return Err("Expected ')' in prototype");
+            args.push(arg);
+        }
+        if self.next() != Some(Token::CloseParenthesis) {
+            return Err(self.unexpected("')' in prototype"));
+        }
+
+        let mut proto = Prototype::new(name, args);
+        match kind {
+            PrototypeKind::Function => {}
+            PrototypeKind::Unary => {
+                if proto.args.len() != 1 {
+                    return Err(self.error("invalid number of operands for operator"));
+                }
+                proto.is_operator = true;
+            }
+            PrototypeKind::Binary(op, precedence) => {
+                if proto.args.len() != 2 {
+                    return Err(self.error("invalid number of operands for operator"));
+                }
+                self.set_precedence(op, precedence);
+                proto.is_operator = true;
+                proto.precedence = Some(precedence);
             }
-            Ok(Prototype { name, args })
-        } else {
-            Err("Expected function name in prototype")
         }
+        Ok(proto)
     }
 
     fn parse_expression(&mut self) -> Result<ExprAST> {
-        let lhs = self.parse_primary()?;
+        let lhs = self.parse_unary()?;
         self.parse_op_and_rhs(0, lhs)
     }
 
+    fn parse_unary(&mut self) -> Result<ExprAST> {
+        if let Some(Token::Operator(op)) = self.peek() {
+            let op = *op;
+            self.next();
+            let span = self.last_span();
+            let operand = self.parse_unary()?;
+            let kind = ExprKind::UnaryOp {
+                op,
+                operand: Box::new(operand),
+            };
+            Ok(ExprAST::new(kind, span))
+        } else {
+            self.parse_primary()
+        }
+    }
+
     fn parse_primary(&mut self) -> Result<ExprAST> {
-        match self.iter.next() {
-            Some(Token::Number(value)) => Ok(ExprAST::Number(value)),
-            Some(Token::Identifier(name)) => {
-                if self.iter.peek() != Some(&Token::OpenParenthesis) {
-                    Ok(ExprAST::Variable(name))
-                } else {
-                    self.iter.next();
-                    let mut args = Vec::new();
-                    if self.iter.peek() != Some(&Token::CloseParenthesis) {
-                        loop {
-                            args.push(self.parse_expression()?);
-                            match self.iter.peek() {
-                                Some(Token::CloseParenthesis) => {
-                                    break;
-                                }
-                                Some(Token::Comma) => {
-                                    self.iter.next();
-                                }
-                                _ => {
-                                    return Err("Expected ')' or ',' in argument list");
-                                }
-                            }
-                        }
+        if matches!(self.peek(), Some(Token::Identifier(_)))
+            && self.peek2() == Some(&Token::OpenParenthesis)
+        {
+            return self.parse_call();
+        }
+        let kind = match self.next() {
+            Some(Token::Number { value, .. }) => ExprKind::Number(value),
+            Some(Token::Integer { value, .. }) => ExprKind::Integer(value),
+            Some(Token::StringLiteral(value)) => ExprKind::StringLiteral(value),
+            Some(Token::Identifier(name)) => ExprKind::Variable(name),
+            Some(Token::OpenParenthesis) => return self.parse_parenthesis(),
+            Some(Token::If) => return self.parse_if(),
+            Some(Token::For) => return self.parse_for(),
+            Some(Token::Var) => return self.parse_var(),
+            _ => return Err(self.unexpected("expression")),
+        };
+        Ok(ExprAST::new(kind, self.last_span()))
+    }
+
+    fn parse_call(&mut self) -> Result<ExprAST> {
+        let callee = match self.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err(self.unexpected("function name")),
+        };
+        let span = self.last_span();
+        self.next(); // consume '('
+        let mut args = Vec::new();
+        if self.peek() != Some(&Token::CloseParenthesis) {
+            loop {
+                args.push(self.parse_expression()?);
+                match self.peek() {
+                    Some(Token::CloseParenthesis) => {
+                        break;
+                    }
+                    Some(Token::Comma) => {
+                        self.next();
+                    }
+                    _ => {
+                        self.next();
+                        return Err(self.unexpected("')' or ',' in argument list"));
                     }
-                    self.iter.next(); // consume ')'
-                    Ok(ExprAST::Call { callee: name, args })
                 }
             }
-            Some(Token::OpenParenthesis) => self.parse_parenthesis(),
-            _ => Err("Expected expression"),
         }
+        self.next(); // consume ')'
+        Ok(ExprAST::new(ExprKind::Call { callee, args }, span))
     }
 
     fn parse_parenthesis(&mut self) -> Result<ExprAST> {
         let ast = self.parse_expression()?;
-        if self.iter.next() == Some(Token::CloseParenthesis) {
+        if self.next() == Some(Token::CloseParenthesis) {
             Ok(ast)
         } else {
-            Err("Expected ')'")
+            Err(self.unexpected("')'"))
+        }
+    }
+
+    fn parse_if(&mut self) -> Result<ExprAST> {
+        let span = self.last_span();
+        let cond = self.parse_expression()?;
+        if self.next() != Some(Token::Then) {
+            return Err(self.unexpected("'then'"));
+        }
+        let then_branch = self.parse_expression()?;
+        if self.next() != Some(Token::Else) {
+            return Err(self.unexpected("'else'"));
+        }
+        let else_branch = self.parse_expression()?;
+        let kind = ExprKind::If {
+            cond: Box::new(cond),
+            then_branch: Box::new(then_branch),
+            else_branch: Box::new(else_branch),
+        };
+        Ok(ExprAST::new(kind, span))
+    }
+
+    fn parse_for(&mut self) -> Result<ExprAST> {
+        let span = self.last_span();
+        let var_name = match self.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err(self.unexpected("identifier after 'for'")),
+        };
+        if self.next() != Some(Token::Operator(Operator::Assign)) {
+            return Err(self.unexpected("'=' after 'for'"));
+        }
+        let start = self.parse_expression()?;
+        if self.next() != Some(Token::Comma) {
+            return Err(self.unexpected("',' after for start value"));
+        }
+        let end = self.parse_expression()?;
+        let step = if self.peek() == Some(&Token::Comma) {
+            self.next();
+            Some(Box::new(self.parse_expression()?))
+        } else {
+            None
+        };
+        if self.next() != Some(Token::In) {
+            return Err(self.unexpected("'in' after for"));
+        }
+        let body = self.parse_expression()?;
+        let kind = ExprKind::For {
+            var_name,
+            start: Box::new(start),
+            end: Box::new(end),
+            step,
+            body: Box::new(body),
+        };
+        Ok(ExprAST::new(kind, span))
+    }
+
+    fn parse_var(&mut self) -> Result<ExprAST> {
+        let span = self.last_span();
+        let mut bindings = Vec::new();
+        loop {
+            let name = match self.next() {
+                Some(Token::Identifier(name)) => name,
+                _ => return Err(self.unexpected("identifier after 'var'")),
+            };
+            let init = if self.peek() == Some(&Token::Operator(Operator::Assign)) {
+                self.next();
+                Some(self.parse_expression()?)
+            } else {
+                None
+            };
+            bindings.push((name, init));
+
+            if self.peek() != Some(&Token::Comma) {
+                break;
+            }
+            self.next();
+        }
+        if self.next() != Some(Token::In) {
+            return Err(self.unexpected("'in' after 'var'"));
         }
+        let body = self.parse_expression()?;
+        let kind = ExprKind::VarIn {
+            bindings,
+            body: Box::new(body),
+        };
+        Ok(ExprAST::new(kind, span))
     }
 
     fn parse_op_and_rhs(&mut self, expr_prec: u8, lhs: ExprAST) -> Result<ExprAST> {
         let mut lhs = lhs;
         loop {
-            if let Some(Token::Operator(op)) = self.iter.peek() {
-                let op = *op;
-                let token_prec = self.get_prec(op);
-                if token_prec < expr_prec {
-                    return Ok(lhs);
+            let (op, token_prec) = match self.peek_binop() {
+                Some((op, prec)) if prec >= expr_prec => (op, prec),
+                _ => return Ok(lhs),
+            };
+
+            self.next();
+            let span = self.last_span();
+
+            // For a right-associative operator, an operator of the same
+            // precedence binds to the right-hand side as well.
+            let rhs_prec = match self.associativity(op) {
+                Associativity::Left => token_prec + 1,
+                Associativity::Right => token_prec,
+            };
+            let mut rhs = self.parse_unary()?;
+            if let Some((_, next_prec)) = self.peek_binop() {
+                if next_prec >= rhs_prec {
+                    rhs = self.parse_op_and_rhs(rhs_prec, rhs)?;
                 }
+            }
+
+            let kind = ExprKind::BinaryOp {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            };
+            lhs = ExprAST::new(kind, span);
+        }
+    }
+
+    fn peek_binop(&mut self) -> Option<(Operator, u8)> {
+        match self.peek() {
+            Some(Token::Operator(op)) => {
+                let op = *op;
+                Some((op, self.get_prec(op)))
+            }
+            _ => None,
+        }
+    }
+
+    fn get_prec(&self, op: Operator) -> u8 {
+        self.binop_precedence
+            .get(&op)
+            .copied()
+            .unwrap_or(DEFAULT_BINARY_PRECEDENCE)
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        self.iter.peek().map(|token| &token.value)
+    }
+
+    fn peek2(&mut self) -> Option<&Token> {
+        self.iter.peek2().map(|token| &token.value)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.last = self.iter.next();
+        self.last.as_ref().map(|token| token.value.clone())
+    }
+
+    /// Creates an error reporting the last consumed token as unexpected.
+    fn unexpected(&self, expected: &'static str) -> ParserError {
+        match &self.last {
+            Some(found) => ParserError::UnexpectedToken {
+                found: found.clone(),
+                expected,
+            },
+            None => ParserError::UnexpectedEof { expected },
+        }
+    }
+
+    /// Where the last consumed token starts.
+    fn last_span(&self) -> Span {
+        match &self.last {
+            Some(token) => token.span(),
+            None => Span { line: 1, col: 1 },
+        }
+    }
+
+    /// Creates an error located at the last consumed token.
+    fn error<S: Into<String>>(&self, message: S) -> ParserError {
+        let Span { line, col } = self.last_span();
+        ParserError::Invalid {
+            message: message.into(),
+            line,
+            col,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> std::result::Result<ExprAST, String> {
+        let tokens = tokenize(input).unwrap();
+        Parser::new(tokens.into_iter())
+            .parse()
+            .map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_peekable2() {
+        let mut iter = Peekable2::new(1..=2);
+        assert_eq!(iter.peek2(), Some(&2));
+        assert_eq!(iter.peek(), Some(&1));
+        assert_eq!(iter.next(), Some(1));
+        assert_eq!(iter.peek2(), None);
+        assert_eq!(iter.peek(), Some(&2));
+        assert_eq!(iter.next(), Some(2));
+        assert_eq!(iter.peek(), None);
+        assert_eq!(iter.peek2(), None);
+        assert_eq!(iter.next(), None);
+
+        let mut iter = Peekable2::new(std::iter::empty::<i32>());
+        assert_eq!(iter.peek2(), None);
+        assert_eq!(iter.peek(), None);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_call() {
+        let var = |name: &str| ExprKind::Variable(name.to_string()).into();
+        assert_eq!(
+            parse("f(x, g());"),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::Call {
+                        callee: "f".to_string(),
+                        args: vec![
+                            var("x"),
+                            ExprKind::Call {
+                                callee: "g".to_string(),
+                                args: Vec::new(),
+                            }
+                            .into(),
+                        ],
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+        assert_eq!(
+            parse("x;"),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(var("x")),
+            }
+            .into())
+        );
+        assert_eq!(
+            parse("f(x;"),
+            Err("expected ')' or ',' in argument list, found ';' at 1:4".to_string())
+        );
+    }
+
+    #[test]
+    fn test_divide() {
+        let ast = parse("def f(a b) a / b;");
+        assert_eq!(
+            ast,
+            Ok(ExprKind::Function {
+                proto: Prototype::new("f".to_string(), vec!["a".to_string(), "b".to_string()]),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::Divide,
+                        lhs: Box::new(ExprKind::Variable("a".to_string()).into()),
+                        rhs: Box::new(ExprKind::Variable("b".to_string()).into()),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+        assert_eq!(
+            parse("def f(a) a /"),
+            Err("expected expression, found end of input".to_string())
+        );
+    }
+
+    #[test]
+    fn test_divide_left_associative() {
+        let num = |value| Box::new(ExprKind::Integer(value).into());
+        assert_eq!(
+            parse("6 / 2 / 3;"),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::Divide,
+                        lhs: Box::new(
+                            ExprKind::BinaryOp {
+                                op: Operator::Divide,
+                                lhs: num(6),
+                                rhs: num(2),
+                            }
+                            .into()
+                        ),
+                        rhs: num(3),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+    }
 
-                self.iter.next();
+    #[test]
+    fn test_set_precedence() {
+        let num = |value| Box::new(ExprKind::Integer(value).into());
+        let tokens = tokenize("1 + 2 * 3;").unwrap();
+        let mut parser = Parser::new(tokens.into_iter());
+        parser.set_precedence(Operator::Plus, 50);
+        assert_eq!(
+            parser.parse(),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::Times,
+                        lhs: Box::new(
+                            ExprKind::BinaryOp {
+                                op: Operator::Plus,
+                                lhs: num(1),
+                                rhs: num(2),
+                            }
+                            .into()
+                        ),
+                        rhs: num(3),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
 
-                let mut rhs = self.parse_primary()?;
-                if let Some(Token::Operator(next_op)) = self.iter.peek() {
-                    let next_op = *next_op;
-                    if token_prec < self.get_prec(next_op) {
-                        rhs = self.parse_op_and_rhs(token_prec + 1, rhs)?;
+        let tokens = tokenize("1 % 2;").unwrap();
+        let mut parser = Parser::new(tokens.into_iter());
+        parser.set_precedence(Operator::Custom('%'), 40);
+        assert_eq!(
+            parser.parse(),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::Custom('%'),
+                        lhs: num(1),
+                        rhs: num(2),
                     }
+                    .into()
+                ),
+            }
+            .into())
+        );
+    }
+
+    #[test]
+    fn test_pretty() {
+        let tokens =
+            tokenize("def f(x) var y = 2 in if x < 1 then -y else g(x, 3.5) * y;").unwrap();
+        let ast = Parser::new(tokens.into_iter()).parse().unwrap();
+        assert_eq!(
+            ast.pretty(0),
+            "\
+Function f(x)
+  VarIn
+    Binding(y)
+      Integer(2)
+    If
+      BinaryOp(<)
+        Variable(x)
+        Integer(1)
+      UnaryOp(-)
+        Variable(y)
+      BinaryOp(*)
+        Call(g)
+          Variable(x)
+          Number(3.5)
+        Variable(y)
+"
+        );
+
+        let tokens = tokenize("1 + 2;").unwrap();
+        let ast = Parser::new(tokens.into_iter()).parse().unwrap();
+        assert_eq!(
+            ast.pretty(4),
+            "    Expression\n      BinaryOp(+)\n        Integer(1)\n        Integer(2)\n"
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let cases = [
+            ("1 + 2 * x;", "(1 + (2 * x))"),
+            ("-f(1.5, \"a\\n\\\"b\\\"\");", "-f(1.5, \"a\\n\\\"b\\\"\")"),
+            ("extern sin(x);", "extern sin(x)"),
+            (
+                "def f(a b) if a < b then a else b;",
+                "def f(a b) if (a < b) then a else b",
+            ),
+            (
+                "for i = 0, i < 10, 2 in var a = i, b in a;",
+                "for i = 0, (i < 10), 2 in var a = i, b in a",
+            ),
+            ("def binary% 5 (a b) a - b;", "def binary% 5(a b) (a - b)"),
+            ("(if a then b else c) + 1;", "((if a then b else c) + 1)"),
+            (
+                "-(for i = 0, i < n in f(i)) * (var x = 1 in x);",
+                "(-(for i = 0, (i < n) in f(i)) * (var x = 1 in x))",
+            ),
+        ];
+        for (input, expected) in cases.iter() {
+            let tokens = tokenize(input).unwrap();
+            let mut parser = Parser::new(tokens.into_iter());
+            let ast = parser.parse().unwrap();
+            assert_eq!(ast.to_string(), *expected);
+            // The output parses back to the same tree.
+            let tokens = tokenize(expected).unwrap();
+            parser.feed(tokens.into_iter());
+            assert_eq!(parser.parse(), Ok(ast));
+        }
+    }
+
+    #[test]
+    fn test_to_source() {
+        let cases = [
+            ("1 + 2 * x;", "1 + 2 * x"),
+            ("(1 + 2) * x;", "(1 + 2) * x"),
+            ("((a - b) - c) - (d - e);", "a - b - c - (d - e)"),
+            ("(a ^ b) ^ c ^ (d ^ e);", "(a ^ b) ^ c ^ d ^ e"),
+            ("x = (y = 1 + 2);", "x = y = 1 + 2"),
+            ("-(a + b) * - -c;", "-(a + b) * - -c"),
+            ("(if a then b else c) + 1;", "(if a then b else c) + 1"),
+            (
+                "f((a < b), (c)) + (for i = 0, (i < n) in (g(i)));",
+                "f(a < b, c) + (for i = 0, i < n in g(i))",
+            ),
+            (
+                "def f(a b) var c = (a + b) in (c * c);",
+                "def f(a b) var c = a + b in c * c",
+            ),
+        ];
+        for (input, expected) in cases.iter() {
+            let ast = parse(input).unwrap();
+            assert_eq!(ast.to_source(), *expected);
+        }
+
+        // Operators without a default precedence keep their parentheses.
+        let ast: ExprAST = ExprKind::BinaryOp {
+            op: Operator::Custom('%'),
+            lhs: Box::new(ExprKind::Variable("a".to_string()).into()),
+            rhs: Box::new(
+                ExprKind::BinaryOp {
+                    op: Operator::Custom('%'),
+                    lhs: Box::new(ExprKind::Variable("b".to_string()).into()),
+                    rhs: Box::new(ExprKind::Variable("c".to_string()).into()),
                 }
+                .into(),
+            ),
+        }
+        .into();
+        assert_eq!(ast.to_source(), "a % (b % c)");
+    }
 
-                lhs = ExprAST::BinaryOp {
-                    op: op,
-                    lhs: Box::new(lhs),
-                    rhs: Box::new(rhs),
-                };
+    #[test]
+    fn test_to_source_roundtrip() {
+        // A linear congruential generator, so that failures are reproducible.
+        struct Random(u64);
+        impl Random {
+            fn below(&mut self, n: u64) -> u64 {
+                self.0 = self
+                    .0
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (self.0 >> 33) % n
+            }
+        }
+
+        fn arbitrary(random: &mut Random, depth: u32) -> ExprAST {
+            const OPERATORS: [Operator; 8] = [
+                Operator::LessThan,
+                Operator::Equal,
+                Operator::Plus,
+                Operator::Minus,
+                Operator::Times,
+                Operator::Divide,
+                Operator::Power,
+                Operator::Assign,
+            ];
+            let var = |random: &mut Random| format!("v{}", random.below(3));
+            let choice = if depth == 0 {
+                random.below(3)
             } else {
-                return Ok(lhs);
+                random.below(9)
+            };
+            let child = |random: &mut Random| Box::new(arbitrary(random, depth - 1));
+            match choice {
+                0 => ExprKind::Integer(random.below(100) as i64).into(),
+                1 => ExprKind::Number(random.below(100) as f64 + 0.5).into(),
+                2 => ExprKind::Variable(var(random)).into(),
+                3 | 4 => {
+                    let op = OPERATORS[random.below(OPERATORS.len() as u64) as usize];
+                    let lhs = if op == Operator::Assign {
+                        Box::new(ExprKind::Variable(var(random)).into())
+                    } else {
+                        child(random)
+                    };
+                    ExprKind::BinaryOp {
+                        op,
+                        lhs,
+                        rhs: child(random),
+                    }
+                    .into()
+                }
+                5 => ExprKind::UnaryOp {
+                    op: Operator::Minus,
+                    operand: child(random),
+                }
+                .into(),
+                6 => ExprKind::Call {
+                    callee: "f".to_string(),
+                    args: (0..random.below(3))
+                        .map(|_| arbitrary(random, depth - 1))
+                        .collect(),
+                }
+                .into(),
+                7 => ExprKind::If {
+                    cond: child(random),
+                    then_branch: child(random),
+                    else_branch: child(random),
+                }
+                .into(),
+                _ => ExprKind::VarIn {
+                    bindings: vec![(var(random), Some(arbitrary(random, depth - 1)))],
+                    body: child(random),
+                }
+                .into(),
             }
         }
+
+        let mut random = Random(42);
+        for _ in 0..1000 {
+            let ast: ExprAST = ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(arbitrary(&mut random, 4)),
+            }
+            .into();
+            let source = ast.to_source();
+            assert_eq!(parse(&format!("{};", source)), Ok(ast), "{}", source);
+        }
     }
 
-    fn get_prec(&self, op: Operator) -> u8 {
-        match op {
-            Operator::LessThan => 10,
-            Operator::Plus => 20,
-            Operator::Minus => 20,
-            Operator::Times => 40,
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let ast = parse("def binary% 5 (a b) if a < b then -f(a, \"s\") else b ^ 2.5;").unwrap();
+        let json = serde_json::to_string(&ast).unwrap();
+        assert!(
+            json.starts_with(r#"{"Function":{"proto":{"name":"binary%""#),
+            "{}",
+            json
+        );
+        assert_eq!(serde_json::from_str::<ExprAST>(&json).unwrap(), ast);
+    }
+
+    #[test]
+    fn test_for() {
+        let ast = parse("for i = 0, i < 10 in f(i);");
+        let body = match ast.map(|ast| ast.kind) {
+            Ok(ExprKind::Function { body, .. }) => body,
+            ast => panic!("{:?}", ast),
+        };
+        assert_eq!(
+            *body,
+            ExprKind::For {
+                var_name: "i".to_string(),
+                start: Box::new(ExprKind::Integer(0).into()),
+                end: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::LessThan,
+                        lhs: Box::new(ExprKind::Variable("i".to_string()).into()),
+                        rhs: Box::new(ExprKind::Integer(10).into()),
+                    }
+                    .into()
+                ),
+                step: None,
+                body: Box::new(
+                    ExprKind::Call {
+                        callee: "f".to_string(),
+                        args: vec![ExprKind::Variable("i".to_string()).into()],
+                    }
+                    .into()
+                ),
+            }
+            .into()
+        );
+
+        match parse("for i = 0, i < 10, 2 in i;").map(|ast| ast.kind) {
+            Ok(ExprKind::Function { body, .. }) => match body.kind {
+                ExprKind::For { step, .. } => {
+                    assert_eq!(step, Some(Box::new(ExprKind::Integer(2).into())))
+                }
+                body => panic!("{:?}", body),
+            },
+            ast => panic!("{:?}", ast),
+        }
+
+        assert_eq!(
+            parse("for i = 0, i < 10 i;"),
+            Err("expected 'in' after for, found identifier 'i' at 1:19".to_string())
+        );
+    }
+
+    #[test]
+    fn test_var() {
+        let var = |name: &str| ExprKind::Variable(name.to_string()).into();
+        let ast = parse("var a = 1, b in a + b;");
+        assert_eq!(
+            ast,
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::VarIn {
+                        bindings: vec![
+                            ("a".to_string(), Some(ExprKind::Integer(1).into())),
+                            ("b".to_string(), None),
+                        ],
+                        body: Box::new(
+                            ExprKind::BinaryOp {
+                                op: Operator::Plus,
+                                lhs: Box::new(var("a")),
+                                rhs: Box::new(var("b")),
+                            }
+                            .into()
+                        ),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+
+        assert_eq!(
+            parse("var a = 1 a;"),
+            Err("expected 'in' after 'var', found identifier 'a' at 1:11".to_string())
+        );
+        assert_eq!(
+            parse("var in a;"),
+            Err("expected identifier after 'var', found 'in' at 1:5".to_string())
+        );
+    }
+
+    #[test]
+    fn test_assign() {
+        let var = |name: &str| Box::new(ExprKind::Variable(name.to_string()).into());
+        assert_eq!(
+            parse("a = b = c + 1;"),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::Assign,
+                        lhs: var("a"),
+                        rhs: Box::new(
+                            ExprKind::BinaryOp {
+                                op: Operator::Assign,
+                                lhs: var("b"),
+                                rhs: Box::new(
+                                    ExprKind::BinaryOp {
+                                        op: Operator::Plus,
+                                        lhs: var("c"),
+                                        rhs: Box::new(ExprKind::Integer(1).into()),
+                                    }
+                                    .into()
+                                ),
+                            }
+                            .into()
+                        ),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+    }
+
+    #[test]
+    fn test_with_precedence() {
+        let var = |name: &str| Box::new(ExprKind::Variable(name.to_string()).into());
+        let tokens = tokenize("a + b * c; a - b * c;").unwrap();
+        let mut precedence = HashMap::new();
+        precedence.insert(Operator::Plus, 50);
+        let mut parser = Parser::with_precedence(tokens.into_iter(), precedence);
+        assert_eq!(
+            parser.parse(),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::Times,
+                        lhs: Box::new(
+                            ExprKind::BinaryOp {
+                                op: Operator::Plus,
+                                lhs: var("a"),
+                                rhs: var("b"),
+                            }
+                            .into()
+                        ),
+                        rhs: var("c"),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+        // Operators missing from the table keep their defaults.
+        assert_eq!(
+            parser.parse(),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::Minus,
+                        lhs: var("a"),
+                        rhs: Box::new(
+                            ExprKind::BinaryOp {
+                                op: Operator::Times,
+                                lhs: var("b"),
+                                rhs: var("c"),
+                            }
+                            .into()
+                        ),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+    }
+
+    #[test]
+    fn test_unknown_operator_precedence() {
+        // An operator without a precedence binds at DEFAULT_BINARY_PRECEDENCE,
+        // between `+` and `*`.
+        let body = |input| match parse(input).map(|ast| ast.kind) {
+            Ok(ExprKind::Function { body, .. }) => body.to_string(),
+            ast => panic!("{:?}", ast),
+        };
+        assert_eq!(body("a | b + c;"), "((a | b) + c)");
+        assert_eq!(body("a + b | c;"), "(a + (b | c))");
+        assert_eq!(body("a | b * c;"), "(a | (b * c))");
+    }
+
+    #[test]
+    fn test_right_associative() {
+        let var = |name: &str| Box::new(ExprKind::Variable(name.to_string()).into());
+        assert_eq!(
+            parse("a = b = c;"),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::Assign,
+                        lhs: var("a"),
+                        rhs: Box::new(
+                            ExprKind::BinaryOp {
+                                op: Operator::Assign,
+                                lhs: var("b"),
+                                rhs: var("c"),
+                            }
+                            .into()
+                        ),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+
+        let tokens = tokenize("a - b - c;").unwrap();
+        let mut parser = Parser::new(tokens.into_iter());
+        parser.set_associativity(Operator::Minus, Associativity::Right);
+        assert_eq!(
+            parser.parse(),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::Minus,
+                        lhs: var("a"),
+                        rhs: Box::new(
+                            ExprKind::BinaryOp {
+                                op: Operator::Minus,
+                                lhs: var("b"),
+                                rhs: var("c"),
+                            }
+                            .into()
+                        ),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+    }
+
+    #[test]
+    fn test_power() {
+        let num = |value| Box::new(ExprKind::Integer(value).into());
+        let pow = |lhs, rhs| {
+            Box::new(
+                ExprKind::BinaryOp {
+                    op: Operator::Power,
+                    lhs,
+                    rhs,
+                }
+                .into(),
+            )
+        };
+        let tokens = tokenize("2 ^ 3 ^ 2; 2 - 3 * 2 ^ 2;").unwrap();
+        let mut parser = Parser::new(tokens.into_iter());
+        assert_eq!(
+            parser.parse(),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: pow(num(2), pow(num(3), num(2))),
+            }
+            .into())
+        );
+        // Left-associative operators around it are unaffected.
+        assert_eq!(
+            parser.parse(),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::Minus,
+                        lhs: num(2),
+                        rhs: Box::new(
+                            ExprKind::BinaryOp {
+                                op: Operator::Times,
+                                lhs: num(3),
+                                rhs: pow(num(2), num(2)),
+                            }
+                            .into()
+                        ),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let span = |line, col| Some(Span { line, col });
+        let ast = parse("def f(x)\n  if x then -x\n  else g(x, 2);").unwrap();
+        assert_eq!(ast.span, span(1, 1));
+        let body = match ast.kind {
+            ExprKind::Function { body, .. } => body,
+            kind => panic!("{:?}", kind),
+        };
+        assert_eq!(body.span, span(2, 3));
+        let (cond, then_branch, else_branch) = match body.kind {
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => (cond, then_branch, else_branch),
+            kind => panic!("{:?}", kind),
+        };
+        assert_eq!(cond.span, span(2, 6));
+        assert_eq!(then_branch.span, span(2, 13));
+        assert_eq!(else_branch.span, span(3, 8));
+        match else_branch.kind {
+            ExprKind::Call { args, .. } => {
+                let spans: Vec<_> = args.iter().map(|arg| arg.span).collect();
+                assert_eq!(spans, vec![span(3, 10), span(3, 13)]);
+            }
+            kind => panic!("{:?}", kind),
+        }
+
+        // A binary operation is located at its operator, and parentheses are
+        // not part of a node.
+        let ast = parse("(a) + (b)").unwrap();
+        assert_eq!(ast.span, span(1, 5));
+
+        // Spans don't take part in comparisons.
+        let var: ExprAST = ExprKind::Variable("x".to_string()).into();
+        assert_eq!(var.span, None);
+        let expected: ExprAST = ExprKind::Function {
+            proto: Prototype::anonymous(),
+            body: Box::new(var),
         }
+        .into();
+        assert_eq!(parse("x"), Ok(expected));
+    }
+
+    #[test]
+    fn test_parse_all() {
+        let tokens = tokenize("def f(x) x+1;; def g(y) y*2; f(1)").unwrap();
+        let asts = Parser::new(tokens.into_iter()).parse_all().unwrap();
+        let names: Vec<_> = asts
+            .iter()
+            .map(|ast| match &ast.kind {
+                ExprKind::Function { proto, .. } => proto.name.as_str(),
+                ast => panic!("{:?}", ast),
+            })
+            .collect();
+        assert_eq!(names, vec!["f", "g", ANONYMOUS_FUNCTION_NAME]);
+
+        let tokens = tokenize("def f(x) x+1;;\n  def g(y) y*2; f(1)").unwrap();
+        let positions: Vec<_> = Parser::new(tokens.into_iter())
+            .parse_all()
+            .unwrap()
+            .iter()
+            .map(|ast| ast.span.map(|span| (span.line, span.col)))
+            .collect();
+        assert_eq!(positions, vec![Some((1, 1)), Some((2, 3)), Some((2, 17))]);
+
+        let tokens = tokenize(";;").unwrap();
+        assert_eq!(Parser::new(tokens.into_iter()).parse_all(), Ok(Vec::new()));
+        let tokens = tokenize("def f(x) x;; f(3);;").unwrap();
+        assert_eq!(
+            Parser::new(tokens.into_iter())
+                .parse_all()
+                .map(|asts| asts.len()),
+            Ok(2)
+        );
+
+        assert_eq!(
+            parse("1 2;"),
+            Err("expected ';' after statement, found number 2 at 1:3".to_string())
+        );
+        // Trailing tokens fail the whole input instead of being dropped.
+        let tokens = tokenize("def f(x) x; 1 + 2 3 + 4;").unwrap();
+        assert_eq!(
+            Parser::new(tokens.into_iter())
+                .parse_all()
+                .map_err(|err| err.to_string()),
+            Err("expected ';' after statement, found number 3 at 1:19".to_string())
+        );
+    }
+
+    #[test]
+    fn test_if() {
+        let var = |name: &str| Box::new(ExprKind::Variable(name.to_string()).into());
+        let ast = parse("if a < b then a else b;");
+        assert_eq!(
+            ast,
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::If {
+                        cond: Box::new(
+                            ExprKind::BinaryOp {
+                                op: Operator::LessThan,
+                                lhs: var("a"),
+                                rhs: var("b"),
+                            }
+                            .into()
+                        ),
+                        then_branch: var("a"),
+                        else_branch: var("b"),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+
+        assert_eq!(
+            parse("if a < b a else b;"),
+            Err("expected 'then', found identifier 'a' at 1:10".to_string())
+        );
+        assert_eq!(
+            parse("if a < b then a;"),
+            Err("expected 'else', found ';' at 1:16".to_string())
+        );
+    }
+
+    #[test]
+    fn test_duplicate_parameters() {
+        assert_eq!(
+            parse("def f(a b a) a + b;"),
+            Err("duplicate parameter 'a' at 1:11".to_string())
+        );
+        assert_eq!(
+            parse("extern g(x x);"),
+            Err("duplicate parameter 'x' at 1:12".to_string())
+        );
+    }
+
+    #[test]
+    fn test_binary_operator() {
+        let tokens = tokenize("def binary| 5 (LHS RHS) LHS; a | b + c;").unwrap();
+        let mut parser = Parser::new(tokens.into_iter());
+        match parser.parse().map(|ast| ast.kind) {
+            Ok(ExprKind::Function { proto, .. }) => {
+                assert_eq!(proto.name, "binary|");
+                assert!(proto.is_operator);
+                assert_eq!(proto.precedence, Some(5));
+            }
+            ast => panic!("{:?}", ast),
+        }
+
+        let var = |name: &str| Box::new(ExprKind::Variable(name.to_string()).into());
+        assert_eq!(
+            parser.parse(),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::Custom('|'),
+                        lhs: var("a"),
+                        rhs: Box::new(
+                            ExprKind::BinaryOp {
+                                op: Operator::Plus,
+                                lhs: var("b"),
+                                rhs: var("c"),
+                            }
+                            .into()
+                        ),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+
+        assert_eq!(
+            parse("def binary| 5 (x) x;"),
+            Err("invalid number of operands for operator at 1:17".to_string())
+        );
+        assert_eq!(
+            parse("def binary| 0 (x y) x;"),
+            Err("invalid precedence: must be 1..100 at 1:13".to_string())
+        );
+
+        let tokens = tokenize("def binary+ 99 (a b) a; 1 + 2 * 3;").unwrap();
+        let mut parser = Parser::new(tokens.into_iter());
+        assert_eq!(
+            parser.parse().map_err(|err| err.to_string()),
+            Err("cannot redefine built-in operator '+' at 1:11".to_string())
+        );
+        assert_eq!(parser.get_prec(Operator::Plus), 20);
+        assert_eq!(
+            parse("def binary< (a b) a;"),
+            Err("cannot redefine built-in operator '<' at 1:11".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unary_operator() {
+        match parse("def unary!(v) if v then 0 else 1;").map(|ast| ast.kind) {
+            Ok(ExprKind::Function { proto, .. }) => {
+                assert_eq!(proto.name, "unary!");
+                assert!(proto.is_operator);
+                assert_eq!(proto.precedence, None);
+            }
+            ast => panic!("{:?}", ast),
+        }
+
+        let var = |name: &str| Box::new(ExprKind::Variable(name.to_string()).into());
+        assert_eq!(
+            parse("!a < b;"),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::LessThan,
+                        lhs: Box::new(
+                            ExprKind::UnaryOp {
+                                op: Operator::Custom('!'),
+                                operand: var("a"),
+                            }
+                            .into()
+                        ),
+                        rhs: var("b"),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+
+        assert_eq!(
+            parse("-a + b;"),
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body: Box::new(
+                    ExprKind::BinaryOp {
+                        op: Operator::Plus,
+                        lhs: Box::new(
+                            ExprKind::UnaryOp {
+                                op: Operator::Minus,
+                                operand: var("a"),
+                            }
+                            .into()
+                        ),
+                        rhs: var("b"),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+
+        let neg = |operand| {
+            Box::new(
+                ExprKind::UnaryOp {
+                    op: Operator::Minus,
+                    operand,
+                }
+                .into(),
+            )
+        };
+        let anonymous = |body| {
+            Ok(ExprKind::Function {
+                proto: Prototype::anonymous(),
+                body,
+            }
+            .into())
+        };
+        assert_eq!(parse("-x;"), anonymous(neg(var("x"))));
+        assert_eq!(parse("- -x;"), anonymous(neg(neg(var("x")))));
+        assert_eq!(
+            parse("a - -b;"),
+            anonymous(Box::new(
+                ExprKind::BinaryOp {
+                    op: Operator::Minus,
+                    lhs: var("a"),
+                    rhs: neg(var("b")),
+                }
+                .into()
+            ))
+        );
+
+        assert_eq!(
+            parse("def unary-(a b) a;"),
+            Err("invalid number of operands for operator at 1:15".to_string())
+        );
+    }
+
+    #[test]
+    fn test_nested_if() {
+        let var = |name: &str| Box::new(ExprKind::Variable(name.to_string()).into());
+        let ast = parse("def f(a b) if a then if b then a else b else 0;");
+        assert_eq!(
+            ast,
+            Ok(ExprKind::Function {
+                proto: Prototype::new("f".to_string(), vec!["a".to_string(), "b".to_string()]),
+                body: Box::new(
+                    ExprKind::If {
+                        cond: var("a"),
+                        then_branch: Box::new(
+                            ExprKind::If {
+                                cond: var("b"),
+                                then_branch: var("a"),
+                                else_branch: var("b"),
+                            }
+                            .into()
+                        ),
+                        else_branch: Box::new(ExprKind::Integer(0).into()),
+                    }
+                    .into()
+                ),
+            }
+            .into())
+        );
+    }
+
+    #[test]
+    fn test_error_position() {
+        let tokens = tokenize("def f(x)\n  (x + 1;").unwrap();
+        let err = Parser::new(tokens.into_iter()).parse().unwrap_err();
+        assert_eq!(
+            err,
+            ParserError::UnexpectedToken {
+                found: Spanned {
+                    value: Token::SemiColon,
+                    line: 2,
+                    col: 9,
+                },
+                expected: "')'",
+            }
+        );
+        assert_eq!(err.to_string(), "expected ')', found ';' at 2:9");
+        assert_eq!(
+            parse("def f(x 3) x;"),
+            Err("expected ')' in prototype, found number 3 at 1:9".to_string())
+        );
+        assert_eq!(
+            parse("def 1(x) x;"),
+            Err("expected function name in prototype, found number 1 at 1:5".to_string())
+        );
+
+        let err: Box<dyn std::error::Error> = Box::new(err);
+        assert_eq!(err.to_string(), "expected ')', found ';' at 2:9");
+
+        let tokens = tokenize("def f(x)").unwrap();
+        assert_eq!(
+            Parser::new(tokens.into_iter()).parse(),
+            Err(ParserError::UnexpectedEof {
+                expected: "expression"
+            })
+        );
+        assert_eq!(
+            parse("def f(x)\n  x +)"),
+            Err("expected expression, found ')' at 2:6".to_string())
+        );
     }
 }
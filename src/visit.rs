@@ -0,0 +1,148 @@
+use crate::lexer::Operator;
+use crate::parser::{ExprAST, ExprKind, Prototype};
+
+/// Walks an `ExprAST` tree. `visit_expr` dispatches each node to the hook for
+/// its variant, and the hooks of nodes with children visit them in source
+/// order by default, so an implementation only overrides the hooks it cares
+/// about. An overriding hook visits the children itself if it still wants
+/// them.
+pub trait Visitor {
+    fn visit_expr(&mut self, expr: &ExprAST) {
+        walk_expr(self, expr);
+    }
+
+    fn visit_number(&mut self, _value: f64) {}
+
+    fn visit_integer(&mut self, _value: i64) {}
+
+    fn visit_string(&mut self, _value: &str) {}
+
+    fn visit_variable(&mut self, _name: &str) {}
+
+    fn visit_binary_op(&mut self, _op: Operator, lhs: &ExprAST, rhs: &ExprAST) {
+        self.visit_expr(lhs);
+        self.visit_expr(rhs);
+    }
+
+    fn visit_unary_op(&mut self, _op: Operator, operand: &ExprAST) {
+        self.visit_expr(operand);
+    }
+
+    fn visit_call(&mut self, _callee: &str, args: &[ExprAST]) {
+        for arg in args {
+            self.visit_expr(arg);
+        }
+    }
+
+    fn visit_if(&mut self, cond: &ExprAST, then_branch: &ExprAST, else_branch: &ExprAST) {
+        self.visit_expr(cond);
+        self.visit_expr(then_branch);
+        self.visit_expr(else_branch);
+    }
+
+    fn visit_for(
+        &mut self,
+        _var_name: &str,
+        start: &ExprAST,
+        end: &ExprAST,
+        step: Option<&ExprAST>,
+        body: &ExprAST,
+    ) {
+        self.visit_expr(start);
+        self.visit_expr(end);
+        if let Some(step) = step {
+            self.visit_expr(step);
+        }
+        self.visit_expr(body);
+    }
+
+    fn visit_var_in(&mut self, bindings: &[(String, Option<ExprAST>)], body: &ExprAST) {
+        for (_, init) in bindings {
+            if let Some(init) = init {
+                self.visit_expr(init);
+            }
+        }
+        self.visit_expr(body);
+    }
+
+    fn visit_prototype(&mut self, _proto: &Prototype) {}
+
+    fn visit_function(&mut self, proto: &Prototype, body: &ExprAST) {
+        self.visit_prototype(proto);
+        self.visit_expr(body);
+    }
+}
+
+/// Calls the hook of `visitor` for the variant of `expr`.
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &ExprAST) {
+    match &expr.kind {
+        ExprKind::Number(value) => visitor.visit_number(*value),
+        ExprKind::Integer(value) => visitor.visit_integer(*value),
+        ExprKind::StringLiteral(value) => visitor.visit_string(value),
+        ExprKind::Variable(name) => visitor.visit_variable(name),
+        ExprKind::BinaryOp { op, lhs, rhs } => visitor.visit_binary_op(*op, lhs, rhs),
+        ExprKind::UnaryOp { op, operand } => visitor.visit_unary_op(*op, operand),
+        ExprKind::Call { callee, args } => visitor.visit_call(callee, args),
+        ExprKind::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => visitor.visit_if(cond, then_branch, else_branch),
+        ExprKind::For {
+            var_name,
+            start,
+            end,
+            step,
+            body,
+        } => visitor.visit_for(var_name, start, end, step.as_deref(), body),
+        ExprKind::VarIn { bindings, body } => visitor.visit_var_in(bindings, body),
+        ExprKind::Prototype(proto) => visitor.visit_prototype(proto),
+        ExprKind::Function { proto, body } => visitor.visit_function(proto, body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::Parser;
+
+    fn parse(input: &str) -> ExprAST {
+        let tokens = tokenize(input).unwrap();
+        Parser::new(tokens.into_iter()).parse().unwrap()
+    }
+
+    #[test]
+    fn test_count_calls() {
+        #[derive(Default)]
+        struct CallCounter {
+            calls: usize,
+            variables: Vec<String>,
+        }
+
+        impl Visitor for CallCounter {
+            fn visit_call(&mut self, _callee: &str, args: &[ExprAST]) {
+                self.calls += 1;
+                for arg in args {
+                    self.visit_expr(arg);
+                }
+            }
+
+            fn visit_variable(&mut self, name: &str) {
+                self.variables.push(name.to_string());
+            }
+        }
+
+        let ast = parse("def fib(x) if x < 3 then 1 else fib(x - 1) + fib(g(x, h()) - 2);");
+        let mut counter = CallCounter::default();
+        counter.visit_expr(&ast);
+        assert_eq!(counter.calls, 4);
+        assert_eq!(counter.variables, vec!["x", "x", "x"]);
+
+        let ast = parse("for i = 0, i < n, f(i) in var a = g(i) in a;");
+        let mut counter = CallCounter::default();
+        counter.visit_expr(&ast);
+        assert_eq!(counter.calls, 2);
+        assert_eq!(counter.variables, vec!["i", "n", "i", "i", "a"]);
+    }
+}
@@ -0,0 +1,265 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn write_source(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("kaleidoscope-{}-{}", std::process::id(), name));
+    fs::write(&path, source).unwrap();
+    path
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_kaleidoscope"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn run_repl(input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_kaleidoscope"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_source_file_argument() {
+    let input = write_source("ok.k", "extern sin(x);\ndef f(x) sin(x) * 2;\nf(1);\n");
+    let output = run(&[input.to_str().unwrap()]);
+    assert!(output.status.success(), "{:?}", output);
+    let ir = String::from_utf8(output.stdout).unwrap();
+    assert!(ir.contains("declare double @sin(double)"), "{}", ir);
+    assert!(ir.contains("define double @f(double %x)"), "{}", ir);
+
+    // The same as the explicit `build` subcommand.
+    let build = run(&["build", input.to_str().unwrap()]);
+    assert_eq!(String::from_utf8(build.stdout).unwrap(), ir);
+
+    let object = input.with_extension("o");
+    let output = run(&[input.to_str().unwrap(), "-o", object.to_str().unwrap()]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(fs::metadata(&object).unwrap().len() > 0);
+
+    fs::remove_file(input).unwrap();
+    fs::remove_file(object).unwrap();
+}
+
+#[test]
+fn test_source_file_errors() {
+    let input = write_source("bad.k", "def f(x) y;\n");
+    let output = run(&[input.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    // Piped output is not colored.
+    assert_eq!(stderr, "error: variable 'y' not found at 1:10\n");
+    fs::remove_file(input).unwrap();
+
+    let output = run(&["does-not-exist.k"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_emit_flags() {
+    let input = write_source("emit.k", "def f(x) x + 1;\nf(41);\n");
+    let input = input.to_str().unwrap();
+
+    let output = run(&["--emit-llvm", input]);
+    assert!(output.status.success(), "{:?}", output);
+    let ir = String::from_utf8(output.stdout).unwrap();
+    assert!(ir.contains("define double @f(double %x)"), "{}", ir);
+
+    let object = std::env::temp_dir().join(format!("kaleidoscope-{}-emit.out", std::process::id()));
+    let output = run(&[input, "--emit-obj", "-o", object.to_str().unwrap()]);
+    assert!(output.status.success(), "{:?}", output);
+    let bytes = fs::read(&object).unwrap();
+    assert!(!bytes.starts_with(b"; ModuleID"));
+
+    // The last emit flag wins over earlier ones and over the extension.
+    let output = run(&[
+        input,
+        "--emit-obj",
+        "--emit-llvm",
+        "-o",
+        object.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "{:?}", output);
+    let text = fs::read_to_string(&object).unwrap();
+    assert!(text.starts_with("; ModuleID"), "{}", text);
+
+    let output = run(&[input, "--emit=nope"]);
+    assert_eq!(output.status.code(), Some(2));
+
+    fs::remove_file(input).unwrap();
+    fs::remove_file(object).unwrap();
+}
+
+#[test]
+fn test_tokens_flag() {
+    let input = write_source("tokens.k", "def f(x)\n  x + 1.5 * 0xFF - 1_000;\n");
+    let output = run(&["--tokens", input.to_str().unwrap()]);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(
+        lines,
+        vec![
+            "'def' at 1:1",
+            "identifier 'f' at 1:5",
+            "'(' at 1:6",
+            "identifier 'x' at 1:7",
+            "')' at 1:8",
+            "identifier 'x' at 2:3",
+            "'+' at 2:5",
+            "number 1.5 at 2:7",
+            "'*' at 2:11",
+            "number 0xFF at 2:13",
+            "'-' at 2:18",
+            "number 1_000 at 2:20",
+            "';' at 2:25",
+        ]
+    );
+    fs::remove_file(&input).unwrap();
+
+    let input = write_source("tokens-bad.k", "1 \"abc");
+    let output = run(&["--tokens", input.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    fs::remove_file(&input).unwrap();
+}
+
+#[test]
+fn test_ast_flag() {
+    let input = write_source("ast.k", "1 + 2 * 3;\n");
+    let output = run(&["--ast", input.to_str().unwrap()]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Expression\n  BinaryOp(+)\n    Integer(1)\n    BinaryOp(*)\n      Integer(2)\n      Integer(3)\n"
+    );
+    fs::remove_file(&input).unwrap();
+}
+
+#[test]
+fn test_repl_load() {
+    // `double` calls `triple` before it is defined, and `|` keeps its
+    // precedence after the file is loaded.
+    let input = write_source(
+        "load.k",
+        "extern sin(x);\ndef double(x) triple(x) / 3 * 2;\ndef triple(x) x * 3;\n\
+         def binary| 5 (a b) if a then a else b;\ndouble(1) | 0;\n",
+    );
+    let bad = write_source("load-bad.k", "def bad(x) y;\n");
+    let output = run_repl(&format!(
+        ":load {}\ndouble(triple(2));\n1 < 0 | 2;\n:load {}\n:load does-not-exist.k\n",
+        input.to_str().unwrap(),
+        bad.to_str().unwrap()
+    ));
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("=> 2\nloaded 4 definitions"), "{}", stdout);
+    assert!(stdout.contains("=> 12"), "{}", stdout);
+    // `(1 < 0) | 2` rather than `1 < (0 | 2)`.
+    assert!(stdout.contains("=> 2\nparser> "), "{}", stdout);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("error: variable 'y' not found"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("No such file"), "{}", stderr);
+    fs::remove_file(input).unwrap();
+    fs::remove_file(bad).unwrap();
+}
+
+#[test]
+fn test_repl_putchard() {
+    let output = run_repl("extern putchard(c);\nputchard(65);\n");
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("A=> 0"), "{}", stdout);
+}
+
+#[test]
+fn test_repl_multiline_comment() {
+    let output = run_repl("1 + /* one\ntwo */ 2;\n");
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("   ...> => 3"), "{}", stdout);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.contains("error"), "{}", stderr);
+
+    // A blank line abandons it like any incomplete statement.
+    let output = run_repl("1 /* one\n\n2;\n");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("=> 2"), "{}", stdout);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("unterminated block comment"), "{}", stderr);
+}
+
+#[test]
+fn test_repl_multiline_string() {
+    let output = run_repl(":ast\nf(\"one\ntwo\");\n");
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("   ...> "), "{}", stdout);
+    assert!(stdout.contains("String(\"one\\ntwo\")"), "{}", stdout);
+}
+
+#[test]
+fn test_repl_commands() {
+    let output = run_repl(":verify\nverify;\n:ast\n1 + 2;\n");
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("module is valid"), "{}", stdout);
+    assert!(stdout.contains("AST dump on"), "{}", stdout);
+    assert!(stdout.contains("  BinaryOp(+)\n"), "{}", stdout);
+    // Without the prefix, it is an ordinary expression.
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("error: variable 'verify' not found"),
+        "{}",
+        stderr
+    );
+
+    let output = run_repl(":help\n:quit\n1 + 2;\n");
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(":load <file>"), "{}", stdout);
+    assert_eq!(
+        stdout.contains(":json"),
+        cfg!(feature = "serde"),
+        "{}",
+        stdout
+    );
+    // Nothing after `:quit` is evaluated.
+    assert!(!stdout.contains("=> 3"), "{}", stdout);
+
+    // `quit` works without the prefix too.
+    let output = run_repl("quit\n1 + 2;\n");
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!stdout.contains("=> 3"), "{}", stdout);
+
+    let output = run_repl(":load\n:frobnicate\n");
+    assert!(output.status.success(), "{:?}", output);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("error: unknown command ':load', type :help"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("error: unknown command ':frobnicate', type :help"),
+        "{}",
+        stderr
+    );
+}